@@ -0,0 +1,303 @@
+//! Game Genie and GameShark cheat codes.
+//!
+//! Cheat files list one code per line, optionally followed by a description. Lines starting
+//! with `#` are comments, and a code prefixed with `-` is loaded disabled:
+//!
+//! ```text
+//! # Infinite lives
+//! 010A1CC1 Lives
+//! -00A-17B-C49 Start on the last level
+//! ```
+
+use crate::Error;
+
+/// A decoded cheat code
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CheatKind {
+    /// Replaces a byte read from ROM, but only when the ROM holds `compare` if given
+    GameGenie {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// Writes a byte to RAM at 0xA000-0xDFFF every frame, to `bank` when given and to the
+    /// mapped bank otherwise
+    GameShark {
+        bank: Option<SharkBank>,
+        address: u16,
+        value: u8,
+    },
+}
+
+/// The bank written by a GameShark code, regardless of the mapped one
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum SharkBank {
+    /// A cartridge RAM bank for 0xA000-0xBFFF
+    CartRam(usize),
+    /// A WRAM bank for 0xD000-0xDFFF
+    Wram(usize),
+}
+
+impl CheatKind {
+    pub fn parse(code: &str) -> Result<CheatKind, Error> {
+        let invalid = || Error::InvalidCheat(code.to_string());
+        let digits = code
+            .chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+
+        match digits.len() {
+            8 if !code.contains('-') => {
+                let kind = byte(0);
+                let bank = match kind {
+                    0x80..=0x8F => Some(SharkBank::CartRam((kind & 0x0F) as usize)),
+                    0x90..=0x97 => Some(SharkBank::Wram(((kind & 0x07) as usize).max(1))),
+                    _ => None,
+                };
+                let address = u16::from_le_bytes([byte(4), byte(6)]);
+                // GameShark codes can only write RAM
+                if !(0xA000..=0xDFFF).contains(&address) {
+                    return Err(invalid());
+                }
+                Ok(CheatKind::GameShark {
+                    bank,
+                    value: byte(2),
+                    address,
+                })
+            }
+            6 | 9 => {
+                let address = ((digits[5] as u16) << 12
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16)
+                    ^ 0xF000;
+                if address >= 0x8000 {
+                    return Err(invalid());
+                }
+                let compare = match digits.len() {
+                    9 => Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA),
+                    _ => None,
+                };
+                Ok(CheatKind::GameGenie {
+                    address,
+                    value: byte(0),
+                    compare,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+pub struct Cheat {
+    pub id: usize,
+    pub code: String,
+    pub description: String,
+    pub enabled: bool,
+    pub kind: CheatKind,
+}
+
+/// The cheats of a device, checked by the MMU on ROM reads and applied once per frame
+#[derive(Default)]
+pub struct Cheats {
+    list: Vec<Cheat>,
+    next_id: usize,
+    /// Enabled Game Genie codes as (address, value, compare)
+    genie: Vec<(u16, u8, Option<u8>)>,
+    /// Enabled GameShark codes as (bank, address, value)
+    shark: Vec<(Option<SharkBank>, u16, u8)>,
+}
+
+impl Cheats {
+    pub fn add(&mut self, code: &str, description: &str, enabled: bool) -> Result<usize, Error> {
+        let kind = CheatKind::parse(code)?;
+        let id = self.push(code, description, enabled, kind);
+        self.update();
+        Ok(id)
+    }
+
+    fn push(&mut self, code: &str, description: &str, enabled: bool, kind: CheatKind) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Cheat {
+            id,
+            code: code.to_string(),
+            description: description.to_string(),
+            enabled,
+            kind,
+        });
+        id
+    }
+
+    /// Returns false when there is no cheat with this id
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.list.iter_mut().find(|c| c.id == id) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                self.update();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|c| c.id != id);
+        self.update();
+        self.list.len() != len
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.list
+    }
+
+    fn update(&mut self) {
+        self.genie.clear();
+        self.shark.clear();
+        for cheat in self.list.iter().filter(|c| c.enabled) {
+            match cheat.kind {
+                CheatKind::GameGenie {
+                    address,
+                    value,
+                    compare,
+                } => self.genie.push((address, value, compare)),
+                CheatKind::GameShark {
+                    bank,
+                    address,
+                    value,
+                } => self.shark.push((bank, address, value)),
+            }
+        }
+    }
+
+    /// Applies the Game Genie codes to `value`, which was read from ROM at `address`
+    #[inline]
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        for &(patch_address, patch_value, compare) in &self.genie {
+            if patch_address == address && compare.unwrap_or(value) == value {
+                return patch_value;
+            }
+        }
+        value
+    }
+
+    pub fn gameshark_writes(&self) -> &[(Option<SharkBank>, u16, u8)] {
+        &self.shark
+    }
+
+    /// Adds all cheats of a cheat file. Nothing is added when any of the codes is invalid.
+    pub fn load(&mut self, text: &str) -> Result<(), Error> {
+        let mut parsed = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            parsed.push((code, description.trim(), enabled, CheatKind::parse(code)?));
+        }
+        for (code, description, enabled, kind) in parsed {
+            self.push(code, description, enabled, kind);
+        }
+        self.update();
+        Ok(())
+    }
+
+    /// Writes all cheats in the cheat file format
+    pub fn save(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.list {
+            if !cheat.enabled {
+                text.push('-');
+            }
+            text.push_str(&cheat.code);
+            if !cheat.description.is_empty() {
+                text.push(' ');
+                text.push_str(&cheat.description);
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CheatKind, Cheats, SharkBank};
+
+    #[test]
+    fn parse_codes() {
+        assert_eq!(
+            CheatKind::parse("421-50F").unwrap(),
+            CheatKind::GameGenie {
+                address: 0x0150,
+                value: 0x42,
+                compare: None
+            }
+        );
+        assert_eq!(
+            CheatKind::parse("421-50F-EEA").unwrap(),
+            CheatKind::GameGenie {
+                address: 0x0150,
+                value: 0x42,
+                compare: Some(0x00)
+            }
+        );
+        assert_eq!(
+            CheatKind::parse("010A1CC1").unwrap(),
+            CheatKind::GameShark {
+                bank: None,
+                address: 0xC11C,
+                value: 0x0A
+            }
+        );
+        assert_eq!(
+            CheatKind::parse("930A00D0").unwrap(),
+            CheatKind::GameShark {
+                bank: Some(SharkBank::Wram(3)),
+                address: 0xD000,
+                value: 0x0A
+            }
+        );
+        assert_eq!(
+            CheatKind::parse("820A00A0").unwrap(),
+            CheatKind::GameShark {
+                bank: Some(SharkBank::CartRam(2)),
+                address: 0xA000,
+                value: 0x0A
+            }
+        );
+        // GameShark codes can only write RAM
+        assert!(CheatKind::parse("01012020").is_err());
+        assert!(CheatKind::parse("010100E0").is_err());
+        assert!(CheatKind::parse("421-50").is_err());
+        assert!(CheatKind::parse("42G-50F").is_err());
+        // Game Genie codes can only patch ROM
+        assert!(CheatKind::parse("421-507").is_err());
+    }
+
+    #[test]
+    fn cheat_file_roundtrip() {
+        let mut cheats = Cheats::default();
+        cheats
+            .load("# Comment\n\n010A1CC1 Infinite lives\n-421-50F-EEA\n")
+            .unwrap();
+        assert_eq!(cheats.list().len(), 2);
+        assert_eq!(cheats.list()[0].description, "Infinite lives");
+        assert!(!cheats.list()[1].enabled);
+        assert_eq!(cheats.save(), "010A1CC1 Infinite lives\n-421-50F-EEA\n");
+        assert!(cheats.load("12345").is_err());
+
+        // A file with an invalid code leaves the list unchanged
+        assert!(cheats.load("01FFC0C0 Valid\n12345 Invalid\n").is_err());
+        assert_eq!(cheats.list().len(), 2);
+        assert_eq!(cheats.gameshark_writes().len(), 1);
+    }
+}
@@ -1,9 +1,12 @@
+use crate::debugger::mapped_rombank;
+use crate::gbmode::HardwareModel;
 use crate::mbc;
 use crate::mmu::MMU;
 use crate::register::CpuFlag::{C, H, N, Z};
 use crate::register::Registers;
 use crate::serial::SerialCallback;
-use crate::StrResult;
+use crate::trace::Tracer;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -18,32 +21,78 @@ pub struct CPU {
     ime: bool,
     setdi: u32,
     setei: u32,
+    #[serde(skip)]
+    pub tracer: Option<Tracer>,
 }
 
-impl CPU {
-    pub fn new(
-        cart: Box<dyn mbc::MBC + 'static>,
-        serial_callback: Option<Box<dyn SerialCallback>>,
-    ) -> StrResult<CPU> {
-        let cpu_mmu = MMU::new(cart, serial_callback)?;
-        let registers = Registers::new(cpu_mmu.gbmode);
-        Ok(CPU {
-            reg: registers,
-            halted: false,
-            halt_bug: false,
-            ime: true,
-            setdi: 0,
-            setei: 0,
-            mmu: cpu_mmu,
-        })
+/// A snapshot of the registers and interrupt state of the CPU
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct CpuState {
+    pub a: u8,
+    /// Flags Z, N, H and C in the upper four bits. The lower four bits are always zero.
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    /// Interrupt master enable
+    pub ime: bool,
+    pub halted: bool,
+    /// Interrupt enable register at 0xFFFF
+    pub inte: u8,
+    /// Interrupt flag register at 0xFF0F
+    pub intf: u8,
+}
+
+impl CpuState {
+    pub fn af(&self) -> u16 {
+        ((self.a as u16) << 8) | ((self.f & 0xF0) as u16)
+    }
+    pub fn bc(&self) -> u16 {
+        ((self.b as u16) << 8) | (self.c as u16)
+    }
+    pub fn de(&self) -> u16 {
+        ((self.d as u16) << 8) | (self.e as u16)
+    }
+    pub fn hl(&self) -> u16 {
+        ((self.h as u16) << 8) | (self.l as u16)
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        self.a = (value >> 8) as u8;
+        self.f = (value & 0x00F0) as u8;
+    }
+    pub fn set_bc(&mut self, value: u16) {
+        self.b = (value >> 8) as u8;
+        self.c = (value & 0x00FF) as u8;
+    }
+    pub fn set_de(&mut self, value: u16) {
+        self.d = (value >> 8) as u8;
+        self.e = (value & 0x00FF) as u8;
     }
+    pub fn set_hl(&mut self, value: u16) {
+        self.h = (value >> 8) as u8;
+        self.l = (value & 0x00FF) as u8;
+    }
+}
 
-    pub fn new_cgb(
+impl CPU {
+    /// Creates a CPU for the given hardware model. Color models run classic games in
+    /// compatibility mode.
+    pub fn new(
         cart: Box<dyn mbc::MBC + 'static>,
         serial_callback: Option<Box<dyn SerialCallback>>,
-    ) -> StrResult<CPU> {
-        let cpu_mmu = MMU::new_cgb(cart, serial_callback)?;
-        let registers = Registers::new(cpu_mmu.gbmode);
+        model: HardwareModel,
+    ) -> Result<CPU, Error> {
+        let cpu_mmu = match model.is_color() {
+            true => MMU::new_cgb(cart, serial_callback, model)?,
+            false => MMU::new(cart, serial_callback, model)?,
+        };
+        let registers = Registers::new(cpu_mmu.gbmode, model);
         Ok(CPU {
             reg: registers,
             halted: false,
@@ -51,6 +100,7 @@ impl CPU {
             ime: true,
             setdi: 0,
             setei: 0,
+            tracer: None,
             mmu: cpu_mmu,
         })
     }
@@ -71,12 +121,28 @@ impl CPU {
             // Emulate a noop instruction
             1
         } else {
+            if self.tracer.is_some() {
+                self.trace();
+            }
             self.call()
         }
     }
 
+    fn trace(&mut self) {
+        let pc = self.reg.pc;
+        let mut pcmem = [0; 4];
+        for (i, byte) in pcmem.iter_mut().enumerate() {
+            *byte = self.mmu.rb_unwatched(pc.wrapping_add(i as u16));
+        }
+        let bank = mapped_rombank(pc, self.mmu.mbc.as_ref());
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.reg, pcmem, bank);
+        }
+    }
+
+    /// Opcode fetches do not trigger watchpoints, which only apply to data accesses
     fn fetchbyte(&mut self) -> u8 {
-        let b = self.mmu.rb(self.reg.pc);
+        let b = self.mmu.rb_unwatched(self.reg.pc);
         if self.halt_bug {
             self.halt_bug = false;
         } else {
@@ -86,7 +152,8 @@ impl CPU {
     }
 
     fn fetchword(&mut self) -> u16 {
-        let w = self.mmu.rw(self.reg.pc);
+        let w = (self.mmu.rb_unwatched(self.reg.pc) as u16)
+            | ((self.mmu.rb_unwatched(self.reg.pc.wrapping_add(1)) as u16) << 8);
         self.reg.pc += 2;
         w
     }
@@ -2534,24 +2601,80 @@ impl CPU {
         self.reg.pc = ((self.reg.pc as u32 as i32) + (n as i32)) as u16;
     }
 
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.reg.a,
+            f: self.reg.af() as u8,
+            b: self.reg.b,
+            c: self.reg.c,
+            d: self.reg.d,
+            e: self.reg.e,
+            h: self.reg.h,
+            l: self.reg.l,
+            sp: self.reg.sp,
+            pc: self.reg.pc,
+            ime: self.ime,
+            halted: self.halted,
+            inte: self.mmu.inte,
+            intf: self.mmu.intf,
+        }
+    }
+
+    /// Replaces the CPU state. Pending EI and DI instructions are cancelled.
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.reg.setaf(state.af());
+        self.reg.b = state.b;
+        self.reg.c = state.c;
+        self.reg.d = state.d;
+        self.reg.e = state.e;
+        self.reg.h = state.h;
+        self.reg.l = state.l;
+        self.reg.sp = state.sp;
+        self.reg.pc = state.pc;
+        self.ime = state.ime;
+        self.setei = 0;
+        self.setdi = 0;
+        self.halted = state.halted;
+        self.halt_bug = false;
+        self.mmu.inte = state.inte;
+        self.mmu.intf = state.intf;
+    }
+
+    /// Maps a boot ROM and resets the registers, so execution starts at 0x0000
+    pub fn map_boot_rom(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.mmu.map_boot_rom(data)?;
+        self.set_state(&CpuState::default());
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+    pub fn sp(&self) -> u16 {
+        self.reg.sp
+    }
+
+    // Accesses from outside of the emulated CPU do not trigger watchpoints
     pub fn read_byte(&mut self, address: u16) -> u8 {
-        self.mmu.rb(address)
+        self.mmu.rb_unwatched(address)
     }
     pub fn write_byte(&mut self, address: u16, byte: u8) {
-        self.mmu.wb(address, byte)
+        self.mmu.wb_unwatched(address, byte)
     }
 
     pub fn read_wide(&mut self, address: u16) -> u16 {
-        self.mmu.rw(address)
+        (self.read_byte(address) as u16) | ((self.read_byte(address.wrapping_add(1)) as u16) << 8)
     }
     pub fn write_wide(&mut self, address: u16, wide: u16) {
-        self.mmu.ww(address, wide)
+        self.write_byte(address, (wide & 0xFF) as u8);
+        self.write_byte(address.wrapping_add(1), (wide >> 8) as u8);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::CPU;
+    use crate::gbmode::HardwareModel;
     use crate::mbc;
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::sync::{Arc, Mutex};
@@ -2604,6 +2727,7 @@ mod test {
             let mut c = match CPU::new(
                 Box::new(cart),
                 Some(Box::new(SerialWrapper(serial.clone()))),
+                HardwareModel::Dmg,
             ) {
                 Err(message) => {
                     panic!("{}", message);
@@ -2638,9 +2762,10 @@ mod test {
 
         {
             let cart = mbc::FileBackedMBC::new(CPUINSTRS.into(), false).unwrap();
-            let mut c = match CPU::new_cgb(
+            let mut c = match CPU::new(
                 Box::new(cart),
                 Some(Box::new(SerialWrapper(serial.clone()))),
+                HardwareModel::Cgb,
             ) {
                 Err(message) => {
                     panic!("{}", message);
@@ -0,0 +1,136 @@
+use crate::mbc::MBC;
+use std::ops::RangeInclusive;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// Why the debugger handed control back to the caller
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum StopReason {
+    /// A single instruction was executed, or the instruction stepped over has finished
+    Step,
+    /// The instruction at `address` is about to be executed
+    Breakpoint { address: u16 },
+    /// The last instruction accessed a watched address
+    Watchpoint {
+        address: u16,
+        value: u8,
+        access: Access,
+    },
+    /// The current function has returned
+    Return,
+    /// The maximum number of ticks has elapsed
+    TicksElapsed,
+}
+
+/// The ROM bank that `address` belongs to with the current banking of the cartridge
+pub(crate) fn mapped_rombank(address: u16, mbc: &dyn MBC) -> Option<usize> {
+    match address {
+        0x0000..=0x3FFF => Some(mbc.lower_rombank()),
+        0x4000..=0x7FFF => Some(mbc.rombank()),
+        _ => None,
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// Only break when this ROM bank is mapped. Applies to addresses in 0x0000-0x7FFF.
+    pub bank: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.retain(|b| *b != breakpoint);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// `bank` is the ROM bank mapped at `pc`, if it is in ROM
+    pub fn is_breakpoint(&self, pc: u16, bank: Option<usize>) -> bool {
+        self.breakpoints.iter().any(|b| {
+            b.address == pc
+                && match (b.bank, bank) {
+                    (Some(wanted), Some(mapped)) => wanted == mapped,
+                    _ => true,
+                }
+        })
+    }
+}
+
+/// Watched address ranges, checked by the MMU on every access
+#[derive(Default)]
+pub struct Watchpoints {
+    ranges: Vec<(RangeInclusive<u16>, WatchKind)>,
+    hit: Option<StopReason>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.ranges.push((range, kind));
+    }
+
+    pub fn remove(&mut self, range: &RangeInclusive<u16>) {
+        self.ranges.retain(|(r, _)| r != range);
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.hit = None;
+    }
+
+    #[inline]
+    pub fn check(&mut self, address: u16, value: u8, access: Access) {
+        if self.ranges.is_empty() || self.hit.is_some() {
+            return;
+        }
+        if self
+            .ranges
+            .iter()
+            .any(|(range, kind)| range.contains(&address) && kind.matches(access))
+        {
+            self.hit = Some(StopReason::Watchpoint {
+                address,
+                value,
+                access,
+            });
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<StopReason> {
+        self.hit.take()
+    }
+}
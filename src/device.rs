@@ -1,29 +1,183 @@
-use crate::cpu::CPU;
-use crate::gbmode::GbMode;
+use crate::cheats::Cheat;
+use crate::cpu::{CpuState, CPU};
+use crate::debugger::{mapped_rombank, Breakpoint, Debugger, StopReason, WatchKind};
+use crate::disasm::{self, Instruction};
+use crate::gbmode::HardwareModel;
+use crate::gpu::{DebugImage, SpriteInfo, TileMap, SCREEN_H, SCREEN_W};
 use crate::keypad::KeypadKey;
-use crate::mbc;
+use crate::mbc::{self, MBC};
+use crate::mmu::MemoryDomain;
+use crate::movie::{Movie, MovieState};
 use crate::printer::GbPrinter;
+use crate::rewind::RewindBuffer;
 use crate::serial;
 use crate::serial::SerialCallback;
+use crate::sgb::{SGB_SCREEN_H, SGB_SCREEN_W};
 use crate::sound;
-use crate::StrResult;
+use crate::trace::{TraceCallback, TraceFilter, Tracer};
+use crate::Error;
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Number of ticks the GPU needs to draw a full frame, including VBlank.
+pub const FRAME_TICKS: u32 = 70224;
 
 #[derive(Serialize, Deserialize)]
 pub struct Device {
-    cpu: CPU,
+    cpu: Box<CPU>,
     save_state: Option<String>,
+    /// Ticks executed past the target of the previous `run_for_cycles` call
+    #[serde(default)]
+    overshoot: u32,
+    #[serde(skip)]
+    rewind: Option<RewindBuffer>,
+    #[serde(skip)]
+    movie: Option<MovieState>,
+    #[serde(skip)]
+    debugger: Debugger,
+}
+
+/// Identifies an rboy save state
+const STATE_MAGIC: &[u8; 4] = b"RBST";
+/// Increment whenever the serialized layout of the emulator state changes
+const STATE_VERSION: u16 = 3;
+/// Magic, format version and global checksum of the ROM
+const STATE_HEADER_LEN: usize = 8;
+
+fn decode_state(data: &[u8], rom_checksum: u16) -> Result<Box<CPU>, Error> {
+    if data.len() < STATE_HEADER_LEN || &data[0..4] != STATE_MAGIC {
+        return Err(Error::NotASaveState);
+    }
+    let version = u16::from_be_bytes([data[4], data[5]]);
+    if version != STATE_VERSION {
+        return Err(Error::SaveStateVersion {
+            expected: STATE_VERSION,
+            actual: version,
+        });
+    }
+    let checksum = u16::from_be_bytes([data[6], data[7]]);
+    if checksum != rom_checksum {
+        return Err(Error::SaveStateRomMismatch {
+            expected: rom_checksum,
+            actual: checksum,
+        });
+    }
+
+    ciborium::de::from_reader(&data[STATE_HEADER_LEN..])
+        .map_err(|e| Error::SaveStateDecode(e.to_string()))
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
         if let Some(path) = &self.save_state {
-            let file = std::fs::File::create(path).unwrap();
-            ciborium::into_writer(&self.cpu, file).unwrap();
+            if let Ok(data) = self.save_state_to_vec() {
+                let _ = std::fs::write(path, data);
+            }
         }
     }
 }
 
+/// Builds a `Device` from ROM data held in memory, without touching the filesystem.
+pub struct DeviceBuilder {
+    romdata: Vec<u8>,
+    ramdata: Option<Vec<u8>>,
+    save_sink: Option<Box<dyn mbc::SaveRamSink>>,
+    rtc_clock: Option<Box<dyn mbc::Clock>>,
+    skip_checksum: bool,
+    model: HardwareModel,
+    boot_rom: Option<Vec<u8>>,
+    save_state: Option<String>,
+}
+
+impl DeviceBuilder {
+    pub fn new(romdata: Vec<u8>) -> DeviceBuilder {
+        DeviceBuilder {
+            romdata,
+            ramdata: None,
+            save_sink: None,
+            rtc_clock: None,
+            skip_checksum: false,
+            model: HardwareModel::Cgb,
+            boot_rom: None,
+            save_state: None,
+        }
+    }
+
+    /// Initial contents of the cartridge RAM, in the format produced by `Device::dumpram`.
+    pub fn with_ram(mut self, ramdata: Vec<u8>) -> DeviceBuilder {
+        self.ramdata = Some(ramdata);
+        self
+    }
+
+    /// Receives the cartridge RAM of battery backed cartridges when the device is dropped.
+    pub fn with_save_sink(mut self, sink: Box<dyn mbc::SaveRamSink>) -> DeviceBuilder {
+        self.save_sink = Some(sink);
+        self
+    }
+
+    /// Clock used by cartridges with a real time clock, instead of the host clock.
+    pub fn with_rtc_clock(mut self, clock: Box<dyn mbc::Clock>) -> DeviceBuilder {
+        self.rtc_clock = Some(clock);
+        self
+    }
+
+    pub fn skip_checksum(mut self, skip_checksum: bool) -> DeviceBuilder {
+        self.skip_checksum = skip_checksum;
+        self
+    }
+
+    /// Run in classic Gameboy mode instead of Gameboy Color mode.
+    pub fn classic_mode(mut self, classic_mode: bool) -> DeviceBuilder {
+        self.model = match classic_mode {
+            true => HardwareModel::Dmg,
+            false => HardwareModel::Cgb,
+        };
+        self
+    }
+
+    /// The emulated hardware. Defaults to the Gameboy Color.
+    pub fn model(mut self, model: HardwareModel) -> DeviceBuilder {
+        self.model = model;
+        self
+    }
+
+    /// Runs a DMG or CGB boot ROM before the cartridge. See `Device::load_boot_rom`.
+    pub fn with_boot_rom(mut self, boot_rom: Vec<u8>) -> DeviceBuilder {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    pub fn save_state(mut self, save_state: Option<String>) -> DeviceBuilder {
+        self.save_state = save_state;
+        self
+    }
+
+    pub fn build(self) -> Result<Device, Error> {
+        let mut cart = mbc::SinkBackedMBC::new(
+            self.romdata,
+            self.skip_checksum,
+            self.ramdata.as_deref(),
+            self.save_sink,
+        )?;
+        if let Some(clock) = self.rtc_clock {
+            cart.set_clock(clock);
+        }
+        let mut cpu = CPU::new(Box::new(cart), None, self.model)?;
+        if let Some(boot_rom) = self.boot_rom {
+            cpu.map_boot_rom(boot_rom)?;
+        }
+        Ok(Device {
+            cpu: Box::new(cpu),
+            save_state: self.save_state,
+            overshoot: 0,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::default(),
+        })
+    }
+}
+
 pub struct StdoutPrinter;
 
 impl SerialCallback for StdoutPrinter {
@@ -38,36 +192,110 @@ impl SerialCallback for StdoutPrinter {
 }
 
 impl Device {
-    pub fn load_state(path: &str) -> Option<Box<Device>> {
-        let file = std::fs::File::open(path).ok()?;
-        let cpu = ciborium::de::from_reader(file).ok()?;
-        Some(Box::new(Device {
+    pub fn builder(romdata: Vec<u8>) -> DeviceBuilder {
+        DeviceBuilder::new(romdata)
+    }
+
+    pub fn load_state(romname: &str, path: &str) -> Result<Box<Device>, Error> {
+        let romdata = std::fs::read(romname).map_err(|e| Error::io(romname, e))?;
+        let data = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        let mut device = Device::from_state_bytes(romdata, &data)?;
+        device.save_state = Some(path.to_string());
+        Ok(Box::new(device))
+    }
+
+    /// Serializes the emulator state, prefixed by a header identifying the format version and
+    /// the loaded ROM. The ROM itself is not included.
+    pub fn save_state_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(STATE_HEADER_LEN);
+        data.extend_from_slice(STATE_MAGIC);
+        data.extend_from_slice(&STATE_VERSION.to_be_bytes());
+        data.extend_from_slice(&self.cpu.mmu.mbc.global_checksum().to_be_bytes());
+        ciborium::into_writer(&self.cpu, &mut data)
+            .map_err(|e| Error::SaveStateEncode(e.to_string()))?;
+        Ok(data)
+    }
+
+    /// Restores a save state created by `save_state_to_vec` for the given ROM.
+    pub fn from_state_bytes(romdata: Vec<u8>, data: &[u8]) -> Result<Device, Error> {
+        if romdata.len() < 0x150 {
+            return Err(Error::RomTooSmall {
+                size: romdata.len(),
+            });
+        }
+        let mut cpu = decode_state(data, mbc::global_checksum(&romdata))?;
+        cpu.mmu.mbc.loadrom(romdata);
+
+        Ok(Device {
             cpu,
-            save_state: Some(path.to_string()),
-        }))
+            save_state: None,
+            overshoot: 0,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::default(),
+        })
+    }
+
+    /// Restores a save state created by `save_state_to_vec` into this device, keeping the
+    /// loaded cartridge ROM, serial callback and audio output.
+    pub fn load_state_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let cpu = decode_state(data, self.cpu.mmu.mbc.global_checksum())?;
+        self.restore_cpu(cpu);
+        Ok(())
+    }
+
+    fn restore_cpu(&mut self, mut cpu: Box<CPU>) {
+        cpu.mmu.mbc.loadrom(self.cpu.mmu.mbc.unloadrom());
+        cpu.mmu.sound = self.cpu.mmu.sound.take();
+        cpu.mmu.watchpoints = std::mem::take(&mut self.cpu.mmu.watchpoints);
+        cpu.mmu.cheats = std::mem::take(&mut self.cpu.mmu.cheats);
+        cpu.tracer = self.cpu.tracer.take();
+        if let Some(callback) = self.cpu.mmu.serial.take_callback() {
+            cpu.mmu.serial.set_callback(callback);
+        }
+        if let Some(callback) = self.cpu.mmu.mbc.take_tone_callback() {
+            cpu.mmu.mbc.set_tone_callback(callback);
+        }
+        if let Some(callback) = self.cpu.mmu.mbc.take_infrared_callback() {
+            cpu.mmu.mbc.set_infrared_callback(callback);
+        }
+        if let Some(source) = self.cpu.mmu.mbc.take_image_source() {
+            cpu.mmu.mbc.set_image_source(source);
+        }
+        self.cpu = cpu;
+        self.overshoot = 0;
     }
 
     pub fn new(
         romname: &str,
         skip_checksum: bool,
         save_state: Option<String>,
-    ) -> StrResult<Device> {
-        let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new(Box::new(cart), None).map(|cpu| Device {
-            cpu: cpu,
-            save_state,
-        })
+    ) -> Result<Device, Error> {
+        Device::new_with_model(romname, skip_checksum, save_state, HardwareModel::Dmg)
     }
 
     pub fn new_cgb(
         romname: &str,
         skip_checksum: bool,
         save_state: Option<String>,
-    ) -> StrResult<Device> {
+    ) -> Result<Device, Error> {
+        Device::new_with_model(romname, skip_checksum, save_state, HardwareModel::Cgb)
+    }
+
+    pub fn new_with_model(
+        romname: &str,
+        skip_checksum: bool,
+        save_state: Option<String>,
+        model: HardwareModel,
+    ) -> Result<Device, Error> {
         let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new_cgb(Box::new(cart), None).map(|cpu| Device {
-            cpu: cpu,
+        CPU::new(Box::new(cart), None, model).map(|cpu| Device {
+            cpu: Box::new(cpu),
             save_state,
+            overshoot: 0,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::default(),
         })
     }
 
@@ -75,11 +303,15 @@ impl Device {
         romdata: Vec<u8>,
         skip_checksum: bool,
         save_state: Option<String>,
-    ) -> StrResult<Device> {
+    ) -> Result<Device, Error> {
         let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new(cart, None).map(|cpu| Device {
-            cpu: cpu,
+        CPU::new(cart, None, HardwareModel::Dmg).map(|cpu| Device {
+            cpu: Box::new(cpu),
             save_state,
+            overshoot: 0,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::default(),
         })
     }
 
@@ -87,18 +319,367 @@ impl Device {
         romdata: Vec<u8>,
         skip_checksum: bool,
         save_state: Option<String>,
-    ) -> StrResult<Device> {
+    ) -> Result<Device, Error> {
         let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new_cgb(cart, None).map(|cpu| Device {
-            cpu: cpu,
+        CPU::new(cart, None, HardwareModel::Cgb).map(|cpu| Device {
+            cpu: Box::new(cpu),
             save_state,
+            overshoot: 0,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::default(),
         })
     }
 
+    pub fn model(&self) -> HardwareModel {
+        self.cpu.mmu.model
+    }
+
+    /// Starts execution at 0x0000 in the given boot ROM, which stays mapped until it writes
+    /// 0xFF50. Classic mode expects a 256 byte DMG boot ROM and color mode a 2304 byte CGB boot
+    /// ROM. Must be called before the first instruction is executed.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), Error> {
+        self.cpu.map_boot_rom(boot_rom)
+    }
+
     pub fn do_cycle(&mut self) -> u32 {
         self.cpu.do_cycle()
     }
 
+    /// Runs until the GPU enters the next VBlank and returns the finished frame together with
+    /// the number of ticks executed. When the LCD is off, this returns after the duration of a
+    /// frame instead.
+    pub fn run_frame(&mut self) -> (&[u8], u32) {
+        let mut ticks = 0;
+        self.apply_movie_input();
+        self.cpu.mmu.apply_gameshark();
+        self.cpu.mmu.gpu.updated = false;
+        while ticks < FRAME_TICKS {
+            ticks += self.cpu.do_cycle();
+            if self.check_and_reset_gpu_updated() && self.cpu.mmu.gpu.is_vblank() {
+                break;
+            }
+        }
+        self.capture_rewind_snapshot();
+        (self.get_gpu_data(), ticks)
+    }
+
+    /// Runs for at least `ticks` ticks and returns the number of ticks executed. Instructions
+    /// are not interrupted, so slightly more ticks may run than requested; this excess is
+    /// deducted from the next call.
+    pub fn run_for_cycles(&mut self, ticks: u32) -> u32 {
+        let target = ticks.saturating_sub(self.overshoot);
+        let mut executed = 0;
+        while executed < target {
+            executed += self.cpu.do_cycle();
+        }
+        self.overshoot = self.overshoot.saturating_sub(ticks) + executed - target;
+        executed
+    }
+
+    /// Stops execution before the instruction at `address` is executed. When `bank` is given,
+    /// only stops while that ROM bank is mapped.
+    pub fn add_breakpoint(&mut self, address: u16, bank: Option<usize>) {
+        self.debugger.add_breakpoint(Breakpoint { address, bank });
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16, bank: Option<usize>) {
+        self.debugger
+            .remove_breakpoint(Breakpoint { address, bank });
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+    }
+
+    /// Stops execution after an instruction that accesses an address in `range`
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.cpu.mmu.watchpoints.add(range, kind);
+    }
+
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>) {
+        self.cpu.mmu.watchpoints.remove(&range);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.cpu.mmu.watchpoints.clear();
+    }
+
+    /// Adds an enabled Game Genie or GameShark code and returns its id. GameShark codes are
+    /// applied at the start of every `run_frame`.
+    pub fn add_cheat(&mut self, code: &str, description: &str) -> Result<usize, Error> {
+        self.cpu.mmu.cheats.add(code, description, true)
+    }
+
+    /// Returns false when there is no cheat with this id
+    pub fn enable_cheat(&mut self, id: usize) -> bool {
+        self.cpu.mmu.cheats.set_enabled(id, true)
+    }
+
+    pub fn disable_cheat(&mut self, id: usize) -> bool {
+        self.cpu.mmu.cheats.set_enabled(id, false)
+    }
+
+    pub fn remove_cheat(&mut self, id: usize) -> bool {
+        self.cpu.mmu.cheats.remove(id)
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        self.cpu.mmu.cheats.list()
+    }
+
+    /// Adds the cheats of a cheat file. See the `cheats` module for the format.
+    pub fn load_cheats<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        self.cpu.mmu.cheats.load(&text)
+    }
+
+    pub fn save_cheats<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        std::fs::write(path, self.cpu.mmu.cheats.save()).map_err(|e| Error::io(path, e))
+    }
+
+    /// Passes a line in the Gameboy Doctor format to `callback` before every instruction that
+    /// matches `filter` is executed.
+    pub fn enable_trace(&mut self, callback: Box<dyn TraceCallback>, filter: TraceFilter) {
+        self.cpu.tracer = Some(Tracer::new(callback, filter));
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.cpu.tracer = None;
+    }
+
+    /// Registers and interrupt state of the CPU
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        self.cpu.set_state(state);
+    }
+
+    /// Decodes the instruction at `address` without triggering watchpoints
+    pub fn disassemble(&mut self, address: u16) -> Instruction {
+        disasm::disassemble(address, |a| self.cpu.mmu.rb_unwatched(a))
+    }
+
+    /// Executes a single instruction, or the dispatch of an interrupt.
+    pub fn step_instruction(&mut self) -> StopReason {
+        self.run_debug(1, |_, _| true, StopReason::Step)
+    }
+
+    /// Executes the next instruction. When it is a CALL or RST, runs until it returns, a
+    /// breakpoint or watchpoint is hit, or `max_ticks` have elapsed.
+    pub fn step_over(&mut self, max_ticks: u32) -> StopReason {
+        let pc = self.cpu.pc();
+        let len = match self.cpu.mmu.rb_unwatched(pc) {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => 3,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => 1,
+            _ => return self.step_instruction(),
+        };
+        let return_address = pc.wrapping_add(len);
+        let sp = self.cpu.sp();
+        self.run_debug(
+            max_ticks,
+            |cpu, _| cpu.pc() == return_address && cpu.sp() >= sp,
+            StopReason::Step,
+        )
+    }
+
+    /// Runs until the current function returns, a breakpoint or watchpoint is hit, or
+    /// `max_ticks` have elapsed.
+    pub fn run_until_return(&mut self, max_ticks: u32) -> StopReason {
+        let sp = self.cpu.sp();
+        self.run_debug(
+            max_ticks,
+            |cpu, opcode| {
+                matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9) && cpu.sp() > sp
+            },
+            StopReason::Return,
+        )
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or `max_ticks` have elapsed.
+    pub fn run_until_break(&mut self, max_ticks: u32) -> StopReason {
+        self.run_debug(max_ticks, |_, _| false, StopReason::TicksElapsed)
+    }
+
+    /// Executes instructions until `done` returns true for the CPU and the opcode that was just
+    /// executed. A breakpoint at the current PC is ignored, so execution can continue from it.
+    fn run_debug<F: FnMut(&CPU, u8) -> bool>(
+        &mut self,
+        max_ticks: u32,
+        mut done: F,
+        reason: StopReason,
+    ) -> StopReason {
+        let mut ticks = 0;
+        let mut first = true;
+        self.cpu.mmu.watchpoints.take_hit();
+        loop {
+            let pc = self.cpu.pc();
+            let bank = mapped_rombank(pc, self.cpu.mmu.mbc.as_ref());
+            if !first && self.debugger.is_breakpoint(pc, bank) {
+                return StopReason::Breakpoint { address: pc };
+            }
+            if ticks >= max_ticks {
+                return StopReason::TicksElapsed;
+            }
+            first = false;
+
+            let opcode = self.cpu.mmu.rb_unwatched(pc);
+            ticks += self.cpu.do_cycle();
+            if let Some(hit) = self.cpu.mmu.watchpoints.take_hit() {
+                return hit;
+            }
+            if done(&self.cpu, opcode) {
+                return reason;
+            }
+        }
+    }
+
+    /// Keeps a snapshot every `interval` frames run through `run_frame`, up to `capacity`
+    /// snapshots, so the emulation can be stepped backwards with `rewind`.
+    pub fn enable_rewind(&mut self, interval: u32, capacity: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, capacity));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Steps back at least `frames` frames, restoring the closest older snapshot. Returns the
+    /// number of frames actually stepped back, which is 0 when no older snapshots are left.
+    /// Rewinding is not possible while a movie is recorded or played.
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, Error> {
+        let rewind = match self.rewind.as_mut() {
+            Some(rewind) => rewind,
+            None => return Ok(0),
+        };
+        if self.movie.is_some() {
+            return Err(Error::RewindDuringMovie);
+        }
+
+        let mut snapshot = None;
+        let mut rewound = 0;
+        while rewound < frames {
+            match rewind.step_back() {
+                Some((s, distance)) => {
+                    snapshot = Some(s);
+                    rewound += distance;
+                }
+                None => break,
+            }
+        }
+
+        match snapshot {
+            Some(snapshot) => {
+                self.load_state_bytes(&snapshot)?;
+                Ok(rewound)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn capture_rewind_snapshot(&mut self) {
+        let take_snapshot = match self.rewind.as_mut() {
+            Some(rewind) => rewind.frame_done(),
+            None => false,
+        };
+        if !take_snapshot {
+            return;
+        }
+        if let Ok(snapshot) = self.save_state_to_vec() {
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(snapshot);
+            }
+        }
+    }
+
+    /// Records the keypad state of every frame run through `run_frame`. The movie starts at
+    /// the current state when `from_state` is set, and at power-on otherwise, in which case
+    /// recording should start right after the device is constructed.
+    pub fn start_movie_recording(&mut self, from_state: bool) -> Result<(), Error> {
+        let start_state = match from_state {
+            true => Some(self.save_state_to_vec()?),
+            false => None,
+        };
+        // A save state includes the cartridge RAM already
+        let start_ram = match !from_state && self.cpu.mmu.mbc.is_battery_backed() {
+            true => Some(self.cpu.mmu.mbc.dumpram()),
+            false => None,
+        };
+        let movie = Movie {
+            rom_checksum: self.cpu.mmu.mbc.global_checksum(),
+            mode: self.cpu.mmu.gbmode as u8,
+            model: self.cpu.mmu.model as u8,
+            start_state,
+            start_ram,
+            frames: Vec::new(),
+        };
+        self.movie = Some(MovieState::Recording {
+            movie,
+            pressed: self.cpu.mmu.keypad.pressed(),
+        });
+        Ok(())
+    }
+
+    /// Replaces the keypad input with the input of the movie, until the movie has ended. A
+    /// movie that starts at power-on should be played on a freshly constructed device.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), Error> {
+        let rom_checksum = self.cpu.mmu.mbc.global_checksum();
+        if movie.rom_checksum != rom_checksum {
+            return Err(Error::MovieRomMismatch {
+                expected: rom_checksum,
+                actual: movie.rom_checksum,
+            });
+        }
+        if movie.mode != self.cpu.mmu.gbmode as u8 || movie.model != self.cpu.mmu.model as u8 {
+            return Err(Error::MovieModeMismatch);
+        }
+        if let Some(state) = &movie.start_state {
+            self.load_state_bytes(state)?;
+        }
+        if let Some(ramdata) = &movie.start_ram {
+            self.cpu.mmu.mbc.loadram(ramdata)?;
+        }
+        self.movie = Some(MovieState::Playing { movie, frame: 0 });
+        Ok(())
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(self.movie, Some(MovieState::Playing { .. }))
+    }
+
+    /// Stops recording or playing a movie, and returns it.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            MovieState::Recording { movie, .. } => Some(movie),
+            MovieState::Playing { movie, .. } => Some(movie),
+        }
+    }
+
+    fn apply_movie_input(&mut self) {
+        let pressed = match self.movie.as_mut() {
+            None => return,
+            Some(MovieState::Recording { movie, pressed }) => {
+                movie.frames.push(*pressed);
+                *pressed
+            }
+            Some(MovieState::Playing { movie, frame }) => match movie.frames.get(*frame) {
+                Some(&pressed) => {
+                    *frame += 1;
+                    pressed
+                }
+                None => {
+                    self.movie = None;
+                    return;
+                }
+            },
+        };
+        self.cpu.mmu.keypad.set_pressed(pressed);
+    }
+
     pub fn set_stdout(&mut self, output: bool) {
         if output {
             self.cpu.mmu.serial.set_callback(Box::new(StdoutPrinter));
@@ -131,14 +712,52 @@ impl Device {
         &self.cpu.mmu.gpu.data
     }
 
+    /// The colored screen with its border when running a Super Gameboy game on the Super Gameboy
+    pub fn get_sgb_data(&self) -> Option<Vec<u8>> {
+        let sgb = self.cpu.mmu.sgb.as_ref()?;
+        Some(sgb.render(&self.cpu.mmu.gpu.data))
+    }
+
+    /// Size of the frames of `get_frame`
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.cpu.mmu.sgb {
+            Some(_) => (SGB_SCREEN_W, SGB_SCREEN_H),
+            None => (SCREEN_W, SCREEN_H),
+        }
+    }
+
+    /// The frame to show, which is the Super Gameboy output when available and the Gameboy
+    /// screen otherwise
+    pub fn get_frame(&self) -> Vec<u8> {
+        self.get_sgb_data()
+            .unwrap_or_else(|| self.get_gpu_data().to_vec())
+    }
+
+    /// Renders the tile data of both VRAM banks
+    pub fn render_tiles(&self) -> DebugImage {
+        self.cpu.mmu.gpu.render_tiles()
+    }
+
+    /// Renders a full tile map, outlining the visible area when it is the background map
+    pub fn render_tilemap(&self, map: TileMap) -> DebugImage {
+        self.cpu.mmu.gpu.render_tilemap(map)
+    }
+
+    /// Decodes the sprite attribute table
+    pub fn oam_entries(&self) -> Vec<SpriteInfo> {
+        self.cpu.mmu.gpu.oam_entries()
+    }
+
+    /// Renders all 40 sprites in OAM order
+    pub fn render_sprites(&self) -> DebugImage {
+        self.cpu.mmu.gpu.render_sprites()
+    }
+
     pub fn enable_audio(&mut self, player: Box<dyn sound::AudioPlayer>, is_on: bool) {
-        match self.cpu.mmu.gbmode {
-            GbMode::Classic => {
-                self.cpu.mmu.sound = Some(sound::Sound::new_dmg(player));
-            }
-            GbMode::Color | GbMode::ColorAsClassic => {
-                self.cpu.mmu.sound = Some(sound::Sound::new_cgb(player));
-            }
+        // Only the classic models corrupt wave RAM when it is accessed while channel 3 plays
+        self.cpu.mmu.sound = match self.cpu.mmu.model.is_color() {
+            true => Some(sound::Sound::new_cgb(player)),
+            false => Some(sound::Sound::new_dmg(player)),
         };
         if is_on {
             if let Some(sound) = self.cpu.mmu.sound.as_mut() {
@@ -153,19 +772,57 @@ impl Device {
         }
     }
 
+    /// While a movie is recorded, key changes take effect at the start of the next frame. While
+    /// a movie is played, they are ignored.
     pub fn keyup(&mut self, key: KeypadKey) {
-        self.cpu.mmu.keypad.keyup(key);
+        match self.movie.as_mut() {
+            None => self.cpu.mmu.keypad.keyup(key),
+            Some(MovieState::Recording { pressed, .. }) => *pressed &= !key.mask(),
+            Some(MovieState::Playing { .. }) => {}
+        }
     }
 
     pub fn keydown(&mut self, key: KeypadKey) {
-        self.cpu.mmu.keypad.keydown(key);
+        match self.movie.as_mut() {
+            None => self.cpu.mmu.keypad.keydown(key),
+            Some(MovieState::Recording { pressed, .. }) => *pressed |= key.mask(),
+            Some(MovieState::Playing { .. }) => {}
+        }
+    }
+
+    /// Replaces the clock used by cartridges with a real time clock. The time of the clock on
+    /// the cartridge is kept.
+    pub fn set_rtc_clock(&mut self, clock: Box<dyn mbc::Clock>) {
+        self.cpu.mmu.mbc.set_clock(clock);
+    }
+
+    /// Tilts cartridges with an accelerometer, like the MBC7. Both axes range from -1.0 to 1.0,
+    /// where positive values tilt to the right and towards the bottom.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cpu.mmu.mbc.set_tilt(x, y);
+    }
+
+    /// Receives the tones played by cartridges with a speaker, like the HuC3.
+    pub fn set_tone_callback(&mut self, callback: Box<dyn mbc::ToneCallback>) {
+        self.cpu.mmu.mbc.set_tone_callback(callback);
+    }
+
+    /// Connects the infrared port of cartridges like the HuC1 and HuC3. Use
+    /// `InfraredLink::pair` to connect two devices to each other.
+    pub fn set_infrared_callback(&mut self, callback: Box<dyn mbc::InfraredCallback>) {
+        self.cpu.mmu.mbc.set_infrared_callback(callback);
+    }
+
+    /// Supplies the images seen by the Pocket Camera, which sees a test pattern otherwise.
+    pub fn set_image_source(&mut self, source: Box<dyn mbc::ImageSource>) {
+        self.cpu.mmu.mbc.set_image_source(source);
     }
 
     pub fn romname(&self) -> String {
         self.cpu.mmu.mbc.romname()
     }
 
-    pub fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    pub fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
         self.cpu.mmu.mbc.loadram(ramdata)
     }
 
@@ -181,6 +838,24 @@ impl Device {
         self.cpu.mmu.mbc.check_and_reset_ram_updated()
     }
 
+    /// Size in bytes of a memory domain of this device
+    pub fn domain_size(&self, domain: MemoryDomain) -> usize {
+        self.cpu.mmu.domain_size(domain)
+    }
+
+    /// Reads the byte at `offset` within `domain`, regardless of the mapped banks. Unlike
+    /// `read_byte`, this never has side effects. Returns None when `offset` is out of range.
+    pub fn peek(&self, domain: MemoryDomain, offset: usize) -> Option<u8> {
+        self.cpu.mmu.peek(domain, offset)
+    }
+
+    /// Writes the byte at `offset` within `domain`, regardless of the mapped banks. Unlike
+    /// `write_byte`, this never switches banks or triggers IO. Returns false when `offset` is
+    /// out of range or `domain` is read-only.
+    pub fn poke(&mut self, domain: MemoryDomain, offset: usize, value: u8) -> bool {
+        self.cpu.mmu.poke(domain, offset, value)
+    }
+
     pub fn read_byte(&mut self, address: u16) -> u8 {
         self.cpu.read_byte(address)
     }
@@ -194,3 +869,624 @@ impl Device {
         self.cpu.write_wide(address, byte)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Device, FRAME_TICKS};
+    use crate::{Access, Error, HardwareModel, MemoryDomain, StopReason, WatchKind};
+
+    fn idle_rom() -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        // JR -2: loop forever at the entry point
+        data[0x100] = 0x18;
+        data[0x101] = 0xFE;
+        data
+    }
+
+    #[test]
+    fn run_frame_stops_at_vblank() {
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        // The first frame is shorter, as the LCD starts at the first line
+        let (_, first) = device.run_frame();
+        assert!(first < FRAME_TICKS);
+        for _ in 0..3 {
+            let (data, ticks) = device.run_frame();
+            assert_eq!(data.len(), crate::SCREEN_W * crate::SCREEN_H * 3);
+            assert!((FRAME_TICKS - 12..=FRAME_TICKS + 12).contains(&ticks));
+        }
+    }
+
+    #[test]
+    fn save_state_roundtrip() {
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        device.run_frame();
+        device.write_byte(0xC000, 0x5A);
+
+        let state = device.save_state_to_vec().unwrap();
+        let mut restored = Device::from_state_bytes(idle_rom(), &state).unwrap();
+        assert_eq!(restored.read_byte(0xC000), 0x5A);
+        assert_eq!(restored.read_byte(0x0100), 0x18);
+        assert_eq!(restored.save_state_to_vec().unwrap(), state);
+
+        device.write_byte(0xC000, 0xA5);
+        device.run_frame();
+        device.load_state_bytes(&state).unwrap();
+        assert_eq!(device.read_byte(0xC000), 0x5A);
+        assert_eq!(device.read_byte(0x0100), 0x18);
+    }
+
+    #[test]
+    fn save_state_excludes_rom() {
+        let mut romdata = vec![0; 4 * 1024 * 1024];
+        romdata[0x147] = 0x19; // MBC5
+        romdata[0x148] = 0x07; // 256 banks
+        romdata[0x100] = 0x18;
+        romdata[0x101] = 0xFE;
+        let device = Device::builder(romdata.clone())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+
+        let state = device.save_state_to_vec().unwrap();
+        assert!(state.len() < 256 * 1024);
+        let mut restored = Device::from_state_bytes(romdata, &state).unwrap();
+        assert_eq!(restored.read_byte(0x0100), 0x18);
+    }
+
+    #[test]
+    fn save_state_after_lower_bank_switch() {
+        let mut romdata = vec![0; 0x100000];
+        romdata[0x147] = 0x01; // MBC1
+        romdata[0x148] = 0x05; // 64 banks
+        romdata[0x100] = 0x18;
+        romdata[0x101] = 0xFE;
+        romdata[0x14E..0x150].copy_from_slice(&[0x12, 0x34]);
+        // Bank 32 has a different header
+        romdata[0x8014E..0x80150].copy_from_slice(&[0x56, 0x78]);
+        let mut device = Device::builder(romdata.clone())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+
+        // Map bank 32 at 0x0000-0x3FFF with banking mode 1
+        device.write_byte(0x6000, 0x01);
+        device.write_byte(0x4000, 0x01);
+        assert_eq!(device.read_byte(0x014E), 0x56);
+
+        let state = device.save_state_to_vec().unwrap();
+        let restored = Device::from_state_bytes(romdata, &state).unwrap();
+        assert_eq!(restored.save_state_to_vec().unwrap(), state);
+        device.load_state_bytes(&state).unwrap();
+    }
+
+    #[test]
+    fn save_state_header_is_checked() {
+        let device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        let state = device.save_state_to_vec().unwrap();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            Device::from_state_bytes(idle_rom(), &bad_magic),
+            Err(Error::NotASaveState)
+        ));
+
+        let mut bad_version = state.clone();
+        bad_version[5] = bad_version[5].wrapping_add(1);
+        assert!(matches!(
+            Device::from_state_bytes(idle_rom(), &bad_version),
+            Err(Error::SaveStateVersion { .. })
+        ));
+
+        let mut other_rom = idle_rom();
+        other_rom[0x14F] = 0x01;
+        assert!(matches!(
+            Device::from_state_bytes(other_rom, &state),
+            Err(Error::SaveStateRomMismatch { .. })
+        ));
+
+        let truncated = &state[..super::STATE_HEADER_LEN];
+        assert!(matches!(
+            Device::from_state_bytes(idle_rom(), truncated),
+            Err(Error::SaveStateDecode(..))
+        ));
+    }
+
+    #[test]
+    fn rewind_restores_older_frames() {
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        device.enable_rewind(2, 4);
+
+        for frame in 0..10 {
+            device.write_byte(0xC000, frame);
+            device.run_frame();
+        }
+
+        // Snapshots were taken after frames 1, 3, 5, 7 and 9, of which the last 4 are kept.
+        // The snapshot of frame 9 is the current state, so it is skipped.
+        assert_eq!(device.read_byte(0xC000), 9);
+        assert_eq!(device.rewind(1).unwrap(), 2);
+        assert_eq!(device.read_byte(0xC000), 7);
+        assert_eq!(device.rewind(3).unwrap(), 4);
+        assert_eq!(device.read_byte(0xC000), 3);
+        assert_eq!(device.rewind(10).unwrap(), 0);
+        assert_eq!(device.read_byte(0xC000), 3);
+
+        // Snapshots are taken every 2 frames after the restored one, and frames run since the
+        // last snapshot are counted as well
+        for frame in 20..23 {
+            device.write_byte(0xC000, frame);
+            device.run_frame();
+        }
+        assert_eq!(device.rewind(1).unwrap(), 1);
+        assert_eq!(device.read_byte(0xC000), 21);
+
+        device.start_movie_recording(false).unwrap();
+        assert!(matches!(device.rewind(1), Err(Error::RewindDuringMovie)));
+    }
+
+    #[test]
+    fn movie_playback_is_deterministic() {
+        use crate::{KeypadKey, Movie};
+
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        device.start_movie_recording(false).unwrap();
+        for frame in 0..20 {
+            match frame {
+                3 => device.keydown(KeypadKey::A),
+                7 => device.keyup(KeypadKey::A),
+                8 => {
+                    device.keydown(KeypadKey::Up);
+                    device.keydown(KeypadKey::Start);
+                    device.keyup(KeypadKey::Up);
+                }
+                _ => {}
+            }
+            device.run_frame();
+        }
+        let movie = device.stop_movie().unwrap();
+        assert_eq!(movie.len(), 20);
+        let expected = device.save_state_to_vec().unwrap();
+
+        let mut replay = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        replay.play_movie(movie).unwrap();
+        for _ in 0..20 {
+            replay.keydown(KeypadKey::B);
+            replay.run_frame();
+        }
+        assert!(replay.is_playing_movie());
+        assert_eq!(replay.save_state_to_vec().unwrap(), expected);
+
+        let mut classic = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+        let movie = replay.stop_movie().unwrap();
+        assert!(matches!(
+            classic.play_movie(movie),
+            Err(Error::MovieModeMismatch)
+        ));
+
+        // The AGB runs in the same mode as the CGB, but starts with different registers
+        let mut agb = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .model(HardwareModel::Agb)
+            .build()
+            .unwrap();
+        agb.start_movie_recording(false).unwrap();
+        agb.run_frame();
+        let movie = agb.stop_movie().unwrap();
+        let mut cgb = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            cgb.play_movie(movie),
+            Err(Error::MovieModeMismatch)
+        ));
+    }
+
+    #[test]
+    fn movie_restores_cartridge_ram_and_rtc() {
+        use crate::{EmulatedClock, KeypadKey};
+
+        let mut rom = idle_rom();
+        rom[0x147] = 0x10; // MBC3 with timer, RAM and battery
+        rom[0x149] = 0x02;
+        // Enable the RAM, increment 0xA000 and loop
+        rom[0x100..0x10C].copy_from_slice(&[
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, 0xFA, 0x00, 0xA0, 0x3C, 0xEA, 0x00, 0xA0,
+        ]);
+        rom[0x10C] = 0x18;
+        rom[0x10D] = 0xFE;
+        let save = |rtc: u64, value: u8| {
+            let mut ramdata = rtc.to_be_bytes().to_vec();
+            ramdata.extend_from_slice(&[value; 0x2000]);
+            ramdata
+        };
+
+        let mut device = Device::builder(rom.clone())
+            .skip_checksum(true)
+            .with_ram(save(1_000_000, 0x40))
+            .with_rtc_clock(Box::new(EmulatedClock::new(0)))
+            .build()
+            .unwrap();
+        device.start_movie_recording(false).unwrap();
+        device.keydown(KeypadKey::A);
+        for _ in 0..5 {
+            device.run_frame();
+        }
+        let movie = device.stop_movie().unwrap();
+        assert_eq!(device.cpu.mmu.mbc.ram()[0], 0x41);
+        let expected = device.save_state_to_vec().unwrap();
+
+        // The save file was written by the recording, so it differs on playback
+        let mut replay = Device::builder(rom)
+            .skip_checksum(true)
+            .with_ram(save(5, 0x41))
+            .with_rtc_clock(Box::new(EmulatedClock::new(0)))
+            .build()
+            .unwrap();
+        replay.play_movie(movie).unwrap();
+        for _ in 0..5 {
+            replay.run_frame();
+        }
+        assert_eq!(replay.cpu.mmu.mbc.dumpram(), device.cpu.mmu.mbc.dumpram());
+        assert_eq!(replay.save_state_to_vec().unwrap(), expected);
+    }
+
+    #[test]
+    fn run_for_cycles_carries_overshoot() {
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        let mut total = 0;
+        for _ in 0..100 {
+            total += device.run_for_cycles(1001);
+        }
+        // Only the excess of the last call remains, which is less than one instruction
+        assert!((100 * 1001..100 * 1001 + 24).contains(&total));
+    }
+
+    /// Calls a function that stores 0x42 at 0xC001, then idles
+    fn call_rom() -> Vec<u8> {
+        let mut data = idle_rom();
+        // CALL 0x0200; JR -2
+        data[0x100..0x105].copy_from_slice(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        // LD A,0x42; LD (0xC001),A; RET
+        data[0x200..0x206].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x01, 0xC0, 0xC9]);
+        data
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut device = Device::builder(call_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        device.add_breakpoint(0x0200, Some(1));
+        device.add_breakpoint(0x0202, Some(0));
+        assert_eq!(
+            device.run_until_break(1000),
+            StopReason::Breakpoint { address: 0x0202 }
+        );
+
+        device.add_watchpoint(0xC000..=0xC0FF, WatchKind::Write);
+        assert_eq!(
+            device.step_instruction(),
+            StopReason::Watchpoint {
+                address: 0xC001,
+                value: 0x42,
+                access: Access::Write
+            }
+        );
+        assert_eq!(device.cpu.pc(), 0x0205);
+        assert_eq!(device.run_until_return(1000), StopReason::Return);
+        assert_eq!(device.cpu.pc(), 0x0103);
+    }
+
+    #[test]
+    fn watchpoints_only_apply_to_data_accesses() {
+        let mut device = Device::builder(call_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        // Opcode fetches, debugger reads and OAM DMA do not read the watched code
+        device.add_watchpoint(0x0000..=0x7FFF, WatchKind::Read);
+        assert_eq!(device.read_byte(0x0100), 0xCD);
+        device.write_byte(0xFF46, 0x01);
+        assert_eq!(device.step_instruction(), StopReason::Step);
+        assert_eq!(device.cpu.pc(), 0x0200);
+
+        // RET reads the return address from the stack
+        device.add_watchpoint(0xFFFC..=0xFFFD, WatchKind::Read);
+        assert_eq!(
+            device.run_until_break(1000),
+            StopReason::Watchpoint {
+                address: 0xFFFC,
+                value: 0x03,
+                access: Access::Read
+            }
+        );
+    }
+
+    #[test]
+    fn step_over_call() {
+        let mut device = Device::builder(call_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(device.step_over(1000), StopReason::Step);
+        assert_eq!(device.cpu.pc(), 0x0103);
+        assert_eq!(device.read_byte(0xC001), 0x42);
+        assert_eq!(device.step_over(1000), StopReason::Step);
+        assert_eq!(device.cpu.pc(), 0x0103);
+    }
+
+    struct TraceLines(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl crate::TraceCallback for TraceLines {
+        fn trace(&mut self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn trace_in_gameboy_doctor_format() {
+        let mut device = Device::builder(call_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+        let lines = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let filter = crate::TraceFilter {
+            pc_range: Some(0x0100..=0x01FF),
+            bank: Some(0),
+        };
+        device.enable_trace(Box::new(TraceLines(lines.clone())), filter);
+
+        device.run_for_cycles(100);
+        let lines = lines.lock().unwrap();
+        assert_eq!(
+            lines[0],
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:CD,00,02,18"
+        );
+        // The called function at 0x0200 is outside of the traced range
+        assert_eq!(
+            lines[1],
+            "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00"
+        );
+    }
+
+    #[test]
+    fn peek_and_poke_ignore_banking() {
+        let mut rom = idle_rom();
+        rom.resize(0x10000, 0);
+        rom[0x143] = 0x80; // CGB
+        rom[0x147] = 0x19; // MBC5
+        rom[0x148] = 0x01; // 4 banks
+        rom[0x8000] = 0xB2;
+        let mut device = Device::builder(rom).skip_checksum(true).build().unwrap();
+
+        assert_eq!(device.domain_size(MemoryDomain::Rom), 0x10000);
+        assert_eq!(device.peek(MemoryDomain::Rom, 0x8000), Some(0xB2));
+        assert_eq!(device.peek(MemoryDomain::Rom, 0x10000), None);
+        // Writing the ROM does not select a bank
+        assert!(device.poke(MemoryDomain::Rom, 0x2000, 0x02));
+        assert_eq!(device.read_byte(0x4000), 0x00);
+
+        assert!(device.poke(MemoryDomain::Wram, 0x3000, 0x33));
+        assert_eq!(device.peek(MemoryDomain::Wram, 0x3000), Some(0x33));
+        assert_ne!(device.read_byte(0xD000), 0x33);
+
+        assert!(device.poke(MemoryDomain::Vram, 0x2000, 0x44));
+        assert_eq!(device.read_byte(0x8000), 0x00);
+        device.write_byte(0xFF4F, 1);
+        assert_eq!(device.read_byte(0x8000), 0x44);
+
+        assert!(device.poke(MemoryDomain::ObjPalette, 0x05, 0x7C));
+        device.write_byte(0xFF6A, 0x05);
+        assert_eq!(device.read_byte(0xFF6B), 0x7C);
+        assert_eq!(device.peek(MemoryDomain::ObjPalette, 0x05), Some(0x7C));
+
+        assert_eq!(
+            device.peek(MemoryDomain::Io, 0x40),
+            Some(device.read_byte(0xFF40))
+        );
+        assert!(!device.poke(MemoryDomain::Io, 0x40, 0));
+        assert!(device.poke(MemoryDomain::Hram, 0x7E, 0x55));
+        assert_eq!(device.read_byte(0xFFFE), 0x55);
+        assert_eq!(device.peek(MemoryDomain::Hram, 0x7F), None);
+    }
+
+    #[test]
+    fn model_sets_initial_registers() {
+        let state = |model| {
+            let device = Device::builder(idle_rom())
+                .skip_checksum(true)
+                .model(model)
+                .build()
+                .unwrap();
+            assert_eq!(device.model(), model);
+            device.cpu_state()
+        };
+        assert_eq!(state(HardwareModel::Dmg).af(), 0x01B0);
+        assert_eq!(state(HardwareModel::Mgb).af(), 0xFFB0);
+        assert_eq!(state(HardwareModel::Sgb).bc(), 0x0014);
+        assert_eq!(state(HardwareModel::Cgb).bc(), 0x0000);
+        let agb = state(HardwareModel::Agb);
+        assert_eq!((agb.af(), agb.bc()), (0x1100, 0x0100));
+    }
+
+    #[test]
+    fn sgb_output_includes_border() {
+        let mut rom = idle_rom();
+        let build = |rom: Vec<u8>| {
+            Device::builder(rom)
+                .skip_checksum(true)
+                .model(HardwareModel::Sgb)
+                .build()
+                .unwrap()
+        };
+        let device = build(rom.clone());
+        assert_eq!(device.screen_size(), (160, 144));
+        assert!(device.get_sgb_data().is_none());
+
+        // The SGB flag is only honoured with the old licensee code 0x33
+        rom[0x146] = 0x03;
+        assert!(build(rom.clone()).get_sgb_data().is_none());
+        rom[0x14B] = 0x33;
+        let mut device = build(rom);
+        device.run_frame();
+        assert_eq!(device.screen_size(), (256, 224));
+        assert_eq!(device.get_frame().len(), 256 * 224 * 3);
+    }
+
+    #[test]
+    fn boot_rom_is_mapped_until_ff50_write() {
+        let mut boot_rom = vec![0; 0x100];
+        // LD SP,$FFFE; LD A,$42 followed by NOPs, then LD A,$01; LDH ($FF50),A
+        boot_rom[..5].copy_from_slice(&[0x31, 0xFE, 0xFF, 0x3E, 0x42]);
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .with_boot_rom(boot_rom)
+            .build()
+            .unwrap();
+
+        assert_eq!(device.cpu_state().pc, 0x0000);
+        assert_eq!(device.read_byte(0x0000), 0x31);
+        assert_eq!(device.read_byte(0x0100), 0x18);
+        while device.cpu_state().pc != 0x0100 {
+            device.step_instruction();
+        }
+        let state = device.cpu_state();
+        assert_eq!((state.a, state.sp), (0x01, 0xFFFE));
+        assert_eq!(device.read_byte(0x0000), 0x00);
+
+        let mut color = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            color.load_boot_rom(vec![0; 0x100]),
+            Err(Error::BootRomSize {
+                expected: 0x900,
+                actual: 0x100
+            })
+        ));
+    }
+
+    #[test]
+    fn cheats_patch_rom_and_write_ram() {
+        let mut rom = idle_rom();
+        rom[0x143] = 0x80; // CGB
+        rom[0x151] = 0x01;
+        let mut device = Device::builder(rom).skip_checksum(true).build().unwrap();
+
+        let patch = device.add_cheat("421-50F", "").unwrap();
+        assert_eq!(device.read_byte(0x0150), 0x42);
+        assert!(device.disable_cheat(patch));
+        assert_eq!(device.read_byte(0x0150), 0x00);
+        assert!(device.enable_cheat(patch));
+        assert_eq!(device.read_byte(0x0150), 0x42);
+        assert!(device.remove_cheat(patch));
+        assert!(!device.remove_cheat(patch));
+        assert_eq!(device.read_byte(0x0150), 0x00);
+
+        // The compare byte must match the ROM
+        device.add_cheat("421-50F-EEE", "").unwrap();
+        assert_eq!(device.read_byte(0x0150), 0x00);
+        device.add_cheat("421-51F-EEE", "").unwrap();
+        assert_eq!(device.read_byte(0x0151), 0x42);
+
+        device.add_cheat("015500C0", "").unwrap();
+        device.add_cheat("936600D0", "WRAM bank 3").unwrap();
+        assert_eq!(device.cheats().len(), 4);
+        device.run_frame();
+        assert_eq!(device.read_byte(0xC000), 0x55);
+        assert_ne!(device.read_byte(0xD000), 0x66);
+        assert_eq!(device.peek(MemoryDomain::Wram, 0x3000), Some(0x66));
+        assert!(device.add_cheat("nonsense", "").is_err());
+
+        // A cartridge RAM bank does not select a WRAM bank, and the missing bank is ignored
+        device.add_cheat("8F7700D0", "").unwrap();
+        device.run_frame();
+        assert_eq!(device.read_byte(0xD000), 0x77);
+        device.add_cheat("938800A0", "").unwrap();
+        device.run_frame();
+        assert_eq!(device.peek(MemoryDomain::Wram, 0x3000), Some(0x66));
+    }
+
+    #[test]
+    fn cpu_state_can_be_set() {
+        let mut device = Device::builder(idle_rom())
+            .skip_checksum(true)
+            .classic_mode(true)
+            .build()
+            .unwrap();
+
+        let mut state = device.cpu_state();
+        assert_eq!(state.pc, 0x0100);
+        assert_eq!(state.af(), 0x01B0);
+        // ADD A,B in WRAM
+        device.write_byte(0xC000, 0x80);
+        state.pc = 0xC000;
+        state.a = 0x3A;
+        state.b = 0xC6;
+        state.f = 0x0F;
+        device.set_cpu_state(&state);
+        assert_eq!(device.cpu_state().f, 0x00);
+
+        device.step_instruction();
+        let state = device.cpu_state();
+        assert_eq!((state.a, state.f, state.pc), (0x00, 0xB0, 0xC001));
+
+        // A pending VBlank interrupt is dispatched when IME is set
+        device.set_cpu_state(&crate::CpuState {
+            ime: true,
+            halted: true,
+            inte: 0x01,
+            intf: 0x01,
+            ..state
+        });
+        device.step_instruction();
+        let state = device.cpu_state();
+        assert_eq!(state.pc, 0x0040);
+        assert_eq!(state.sp, 0xFFFC);
+        assert!(!state.ime && !state.halted);
+        assert_eq!(state.intf & 0x01, 0);
+    }
+}
@@ -0,0 +1,237 @@
+//! Disassembler for the SM83 instruction set of the Gameboy CPU
+
+use std::fmt;
+
+/// A decoded instruction
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub operands: Vec<String>,
+    /// Length in bytes, including the CB prefix and immediate operands
+    pub length: u8,
+    /// Machine cycles (4 clock ticks each). For conditional jumps, calls and returns, this is
+    /// the duration when the condition is not met.
+    pub cycles: u32,
+    /// Machine cycles of a conditional jump, call or return when the condition is met
+    pub branch_cycles: Option<u32>,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands.join(","))
+        }
+    }
+}
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const BITOPS: [&str; 4] = ["", "BIT", "RES", "SET"];
+const ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+/// Decodes the instruction at `address`, reading memory through `read`.
+///
+/// Opcodes that do not exist on the SM83 decode as `DB` with the opcode as operand.
+pub fn disassemble<F: FnMut(u16) -> u8>(address: u16, mut read: F) -> Instruction {
+    let opcode = read(address);
+    let d8 = read(address.wrapping_add(1));
+    let d16 = u16::from_le_bytes([d8, read(address.wrapping_add(2))]);
+    if opcode == 0xCB {
+        return decode_cb(d8);
+    }
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let p = (y >> 1) as usize;
+    let q = y & 1;
+    let (r_y, r_z) = (R[y as usize], R[z as usize]);
+    let hl_cycle = |r: &str| if r == "(HL)" { 1 } else { 0 };
+    let imm8 = format!("${:02X}", d8);
+    let imm16 = format!("${:04X}", d16);
+    let relative = format!(
+        "${:04X}",
+        address.wrapping_add(2).wrapping_add(d8 as i8 as u16)
+    );
+    let offset = if (d8 as i8) < 0 {
+        format!("SP-${:02X}", (d8 as i8).unsigned_abs())
+    } else {
+        format!("SP+${:02X}", d8)
+    };
+
+    let i = |mnemonic, operands: &[&str], length, cycles| Instruction {
+        mnemonic,
+        operands: operands.iter().map(|o| o.to_string()).collect(),
+        length,
+        cycles,
+        branch_cycles: None,
+    };
+    let branch = |mnemonic, operands: &[&str], length, cycles, taken| Instruction {
+        branch_cycles: Some(taken),
+        ..i(mnemonic, operands, length, cycles)
+    };
+    let illegal = || i("DB", &[&format!("${:02X}", opcode)], 1, 1);
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => i("NOP", &[], 1, 1),
+            1 => i("LD", &[&format!("({})", imm16), "SP"], 3, 5),
+            2 => i("STOP", &[], 2, 1),
+            3 => i("JR", &[&relative], 2, 3),
+            _ => branch("JR", &[CC[y as usize - 4], &relative], 2, 2, 3),
+        },
+        (0, 1) if q == 0 => i("LD", &[RP[p], &imm16], 3, 3),
+        (0, 1) => i("ADD", &["HL", RP[p]], 1, 2),
+        (0, 2) => {
+            let indirect = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            match q {
+                0 => i("LD", &[indirect, "A"], 1, 2),
+                _ => i("LD", &["A", indirect], 1, 2),
+            }
+        }
+        (0, 3) => i(["INC", "DEC"][q as usize], &[RP[p]], 1, 2),
+        (0, 4) => i("INC", &[r_y], 1, 1 + 2 * hl_cycle(r_y)),
+        (0, 5) => i("DEC", &[r_y], 1, 1 + 2 * hl_cycle(r_y)),
+        (0, 6) => i("LD", &[r_y, &imm8], 2, 2 + hl_cycle(r_y)),
+        (0, _) => i(ACC[y as usize], &[], 1, 1),
+        (1, 6) if y == 6 => i("HALT", &[], 1, 1),
+        (1, _) => i("LD", &[r_y, r_z], 1, 1 + hl_cycle(r_y) + hl_cycle(r_z)),
+        (2, _) => alu(y, r_z, 1, 1 + hl_cycle(r_z)),
+        (_, 0) => match y {
+            0..=3 => branch("RET", &[CC[y as usize]], 1, 2, 5),
+            4 => i(
+                "LDH",
+                &[&format!("(${:04X})", 0xFF00 | d8 as u16), "A"],
+                2,
+                3,
+            ),
+            5 => i("ADD", &["SP", &imm8], 2, 4),
+            6 => i(
+                "LDH",
+                &["A", &format!("(${:04X})", 0xFF00 | d8 as u16)],
+                2,
+                3,
+            ),
+            _ => i("LD", &["HL", &offset], 2, 3),
+        },
+        (_, 1) if q == 0 => i("POP", &[RP2[p]], 1, 3),
+        (_, 1) => match p {
+            0 => i("RET", &[], 1, 4),
+            1 => i("RETI", &[], 1, 4),
+            2 => i("JP", &["HL"], 1, 1),
+            _ => i("LD", &["SP", "HL"], 1, 2),
+        },
+        (_, 2) => match y {
+            0..=3 => branch("JP", &[CC[y as usize], &imm16], 3, 3, 4),
+            4 => i("LD", &["($FF00+C)", "A"], 1, 2),
+            5 => i("LD", &[&format!("({})", imm16), "A"], 3, 4),
+            6 => i("LD", &["A", "($FF00+C)"], 1, 2),
+            _ => i("LD", &["A", &format!("({})", imm16)], 3, 4),
+        },
+        (_, 3) => match y {
+            0 => i("JP", &[&imm16], 3, 4),
+            6 => i("DI", &[], 1, 1),
+            7 => i("EI", &[], 1, 1),
+            _ => illegal(),
+        },
+        (_, 4) if y < 4 => branch("CALL", &[CC[y as usize], &imm16], 3, 3, 6),
+        (_, 5) if q == 0 => i("PUSH", &[RP2[p]], 1, 4),
+        (_, 5) if p == 0 => i("CALL", &[&imm16], 3, 6),
+        (_, 6) => alu(y, &imm8, 2, 2),
+        (_, 7) => i("RST", &[&format!("${:02X}", y * 8)], 1, 4),
+        _ => illegal(),
+    }
+}
+
+fn alu(y: u8, operand: &str, length: u8, cycles: u32) -> Instruction {
+    let operands = match y {
+        0 | 1 | 3 => vec!["A".to_string(), operand.to_string()],
+        _ => vec![operand.to_string()],
+    };
+    Instruction {
+        mnemonic: ALU[y as usize],
+        operands,
+        length,
+        cycles,
+        branch_cycles: None,
+    }
+}
+
+fn decode_cb(opcode: u8) -> Instruction {
+    let x = (opcode >> 6) as usize;
+    let y = (opcode >> 3) & 7;
+    let r = R[(opcode & 7) as usize];
+    let (mnemonic, operands) = match x {
+        0 => (ROT[y as usize], vec![r.to_string()]),
+        _ => (BITOPS[x], vec![y.to_string(), r.to_string()]),
+    };
+    let cycles = match (x, r) {
+        (1, "(HL)") => 3,
+        (_, "(HL)") => 4,
+        _ => 2,
+    };
+    Instruction {
+        mnemonic,
+        operands,
+        length: 2,
+        cycles,
+        branch_cycles: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::disassemble;
+
+    fn decode(bytes: &[u8], address: u16) -> String {
+        let instruction = disassemble(address, |a| {
+            bytes
+                .get(a.wrapping_sub(address) as usize)
+                .copied()
+                .unwrap_or(0)
+        });
+        format!(
+            "{} {} {} {:?}",
+            instruction, instruction.length, instruction.cycles, instruction.branch_cycles
+        )
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(decode(&[0x00], 0), "NOP 1 1 None");
+        assert_eq!(decode(&[0x01, 0x34, 0x12], 0), "LD BC,$1234 3 3 None");
+        assert_eq!(decode(&[0x08, 0x00, 0xC0], 0), "LD ($C000),SP 3 5 None");
+        assert_eq!(decode(&[0x20, 0xFE], 0x150), "JR NZ,$0150 2 2 Some(3)");
+        assert_eq!(decode(&[0x22], 0), "LD (HL+),A 1 2 None");
+        assert_eq!(decode(&[0x36, 0x42], 0), "LD (HL),$42 2 3 None");
+        assert_eq!(decode(&[0x35], 0), "DEC (HL) 1 3 None");
+        assert_eq!(decode(&[0x76], 0), "HALT 1 1 None");
+        assert_eq!(decode(&[0x7E], 0), "LD A,(HL) 1 2 None");
+        assert_eq!(decode(&[0x8A], 0), "ADC A,D 1 1 None");
+        assert_eq!(decode(&[0xBE], 0), "CP (HL) 1 2 None");
+        assert_eq!(decode(&[0xC0], 0), "RET NZ 1 2 Some(5)");
+        assert_eq!(decode(&[0xCD, 0x00, 0x02], 0), "CALL $0200 3 6 None");
+        assert_eq!(decode(&[0xDC, 0x00, 0x02], 0), "CALL C,$0200 3 3 Some(6)");
+        assert_eq!(decode(&[0xE0, 0x40], 0), "LDH ($FF40),A 2 3 None");
+        assert_eq!(decode(&[0xE2], 0), "LD ($FF00+C),A 1 2 None");
+        assert_eq!(decode(&[0xF8, 0xFE], 0), "LD HL,SP-$02 2 3 None");
+        assert_eq!(decode(&[0xFE, 0x90], 0), "CP $90 2 2 None");
+        assert_eq!(decode(&[0xFF], 0), "RST $38 1 4 None");
+        assert_eq!(decode(&[0xD3], 0), "DB $D3 1 1 None");
+    }
+
+    #[test]
+    fn cb_instructions() {
+        assert_eq!(decode(&[0xCB, 0x11], 0), "RL C 2 2 None");
+        assert_eq!(decode(&[0xCB, 0x37], 0), "SWAP A 2 2 None");
+        assert_eq!(decode(&[0xCB, 0x46], 0), "BIT 0,(HL) 2 3 None");
+        assert_eq!(decode(&[0xCB, 0xBE], 0), "RES 7,(HL) 2 4 None");
+        assert_eq!(decode(&[0xCB, 0xFF], 0), "SET 7,A 2 2 None");
+    }
+}
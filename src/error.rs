@@ -0,0 +1,122 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io { path: PathBuf, source: io::Error },
+    /// The ROM is too small to contain a cartridge header
+    RomTooSmall { size: usize },
+    /// The cartridge type in the header (0x0147) is not emulated
+    UnsupportedCartridge { cartridge_type: u8 },
+    /// The header checksum stored at 0x014D does not match the one computed from the header
+    ChecksumMismatch { expected: u8, actual: u8 },
+    /// Loaded cartridge RAM does not have the size the cartridge expects
+    RamSizeMismatch { expected: usize, actual: usize },
+    /// The game only runs on a Gameboy Color, but classic mode was requested
+    ColorOnly,
+    /// The data is not an rboy save state
+    NotASaveState,
+    /// The save state was created by an incompatible version of rboy
+    SaveStateVersion { expected: u16, actual: u16 },
+    /// The save state belongs to a ROM with a different global checksum
+    SaveStateRomMismatch { expected: u16, actual: u16 },
+    /// The save state could not be encoded
+    SaveStateEncode(String),
+    /// The save state could not be decoded
+    SaveStateDecode(String),
+    /// The data is not a valid rboy movie
+    InvalidMovie(&'static str),
+    /// The movie was recorded with a ROM with a different global checksum
+    MovieRomMismatch { expected: u16, actual: u16 },
+    /// The movie was recorded in a different Gameboy mode or on a different model
+    MovieModeMismatch,
+    /// Rewinding would break the input of a movie that is recorded or played
+    RewindDuringMovie,
+    /// The boot ROM does not have the size of a boot ROM for the selected mode
+    BootRomSize { expected: usize, actual: usize },
+    /// The text is not a valid Game Genie or GameShark code
+    InvalidCheat(String),
+    /// The image for the Pocket Camera could not be decoded
+    InvalidImage(&'static str),
+    /// The connection with a GDB debugger failed
+    Gdb(io::Error),
+}
+
+impl Error {
+    pub(crate) fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> Error {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::RomTooSmall { size } => write!(f, "ROM of {} bytes is too small", size),
+            Error::UnsupportedCartridge { cartridge_type } => {
+                write!(f, "Unsupported cartridge type {:02X}", cartridge_type)
+            }
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Cartridge checksum is invalid (header has {:02X}, computed {:02X})",
+                expected, actual
+            ),
+            Error::RamSizeMismatch { expected, actual } => write!(
+                f,
+                "Loaded RAM has incorrect length (expected {} bytes, got {})",
+                expected, actual
+            ),
+            Error::ColorOnly => write!(f, "This game does not work in Classic mode"),
+            Error::NotASaveState => write!(f, "Not an rboy save state"),
+            Error::SaveStateVersion { expected, actual } => write!(
+                f,
+                "Save state has format version {}, but version {} is required",
+                actual, expected
+            ),
+            Error::SaveStateRomMismatch { expected, actual } => write!(
+                f,
+                "Save state belongs to a ROM with checksum {:04X}, but the ROM has {:04X}",
+                actual, expected
+            ),
+            Error::SaveStateEncode(message) => {
+                write!(f, "Could not encode save state: {}", message)
+            }
+            Error::SaveStateDecode(message) => {
+                write!(f, "Could not decode save state: {}", message)
+            }
+            Error::InvalidMovie(message) => write!(f, "Invalid movie: {}", message),
+            Error::MovieRomMismatch { expected, actual } => write!(
+                f,
+                "Movie was recorded with a ROM with checksum {:04X}, but the ROM has {:04X}",
+                actual, expected
+            ),
+            Error::MovieModeMismatch => {
+                write!(f, "Movie was recorded in a different mode or model")
+            }
+            Error::RewindDuringMovie => write!(f, "Cannot rewind while a movie is active"),
+            Error::BootRomSize { expected, actual } => write!(
+                f,
+                "Boot ROM must be {} bytes for this mode, but is {} bytes",
+                expected, actual
+            ),
+            Error::InvalidCheat(code) => write!(f, "Invalid cheat code: {}", code),
+            Error::InvalidImage(message) => write!(f, "Invalid image: {}", message),
+            Error::Gdb(source) => write!(f, "GDB connection failed: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Gdb(source) => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -12,3 +12,29 @@ pub enum GbSpeed {
     Single = 1,
     Double = 2,
 }
+
+/// The Gameboy hardware that is emulated. Games detect the model from the initial registers.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum HardwareModel {
+    /// The original Gameboy
+    #[default]
+    Dmg,
+    /// Gameboy Pocket
+    Mgb,
+    /// Super Gameboy
+    Sgb,
+    /// Gameboy Color
+    Cgb,
+    /// Gameboy Advance
+    Agb,
+}
+
+impl HardwareModel {
+    /// Whether the model can run Gameboy Color games
+    pub fn is_color(self) -> bool {
+        match self {
+            HardwareModel::Cgb | HardwareModel::Agb => true,
+            HardwareModel::Dmg | HardwareModel::Mgb | HardwareModel::Sgb => false,
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! A stub for the GDB remote serial protocol, so a debugger can control a `Device` over TCP.
+//!
+//! The registers are exposed as AF, BC, DE, HL, SP and PC, each as a 16-bit little-endian value.
+
+use crate::debugger::Access;
+use crate::device::{Device, FRAME_TICKS};
+use crate::{CpuState, Error, StopReason, WatchKind};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+const REGISTER_COUNT: usize = 6;
+/// Sent by the debugger to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+/// The largest packet, including the framing, that is advertised in `qSupported`
+const PACKET_SIZE: usize = 0x4000;
+/// Memory reads reply with two hex digits per byte, framed by `$`, `#` and the checksum
+const MAX_READ_LEN: u16 = ((PACKET_SIZE - 4) / 2) as u16;
+
+/// Listens for a debugger on localhost
+pub struct GdbStub {
+    listener: TcpListener,
+}
+
+impl GdbStub {
+    /// Listens on the given port of localhost. Port 0 picks a free port.
+    pub fn bind(port: u16) -> Result<GdbStub, Error> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(Error::Gdb)?;
+        Ok(GdbStub { listener })
+    }
+
+    pub fn port(&self) -> Result<u16, Error> {
+        Ok(self.listener.local_addr().map_err(Error::Gdb)?.port())
+    }
+
+    /// Waits for a debugger to connect and lets it control `device` until it detaches, kills
+    /// the session or disconnects. The device does not run while no debugger is attached.
+    pub fn serve(&self, device: &mut Device) -> Result<(), Error> {
+        let (stream, _) = self.listener.accept().map_err(Error::Gdb)?;
+        stream.set_nodelay(true).map_err(Error::Gdb)?;
+        let mut session = Session {
+            stream,
+            device,
+            ack: true,
+        };
+        session.run().map_err(Error::Gdb)
+    }
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    device: &'a mut Device,
+    ack: bool,
+}
+
+enum Reply {
+    Packet(String),
+    /// Sends the reply and ends the session
+    Close(String),
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.write_packet(&reply)?,
+                Reply::Close(reply) => {
+                    self.write_packet(&reply)?;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, or None when the debugger disconnected
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                // Acknowledgements and interrupts while the target is stopped
+                Some(_) => {}
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if self.ack {
+            let valid = expected == Some(checksum_of(&data));
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            if !valid {
+                return self.read_packet();
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => self.read_registers(),
+            "G" => ok_or_error(self.write_registers(args)),
+            "p" => self
+                .read_register(args)
+                .unwrap_or_else(|| "E01".to_string()),
+            "P" => ok_or_error(self.write_register(args)),
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => ok_or_error(self.write_memory(args)),
+            "Z" => ok_or_error(self.breakpoint(args, true)),
+            "z" => ok_or_error(self.breakpoint(args, false)),
+            "c" => self.resume(args, false),
+            "s" => self.resume(args, true),
+            "H" => "OK".to_string(),
+            "D" => return Reply::Close("OK".to_string()),
+            "k" => return Reply::Close(String::new()),
+            _ => self.query(packet),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};QStartNoAckMode+", PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            self.ack = false;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            // An empty reply marks the packet as unsupported
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        registers_to_array(&self.device.cpu_state())
+            .iter()
+            .map(|v| hex_le(*v))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<()> {
+        let mut values = [0; REGISTER_COUNT];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = parse_le(args.get(i * 4..i * 4 + 4)?)?;
+        }
+        let mut reg = self.device.cpu_state();
+        array_to_registers(&mut reg, values);
+        self.device.set_cpu_state(&reg);
+        Some(())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        let values = registers_to_array(&self.device.cpu_state());
+        values.get(index).map(|v| hex_le(*v))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<()> {
+        let (index, value) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let mut reg = self.device.cpu_state();
+        let mut values = registers_to_array(&reg);
+        *values.get_mut(index)? = parse_le(value)?;
+        array_to_registers(&mut reg, values);
+        self.device.set_cpu_state(&reg);
+        Some(())
+    }
+
+    /// Longer reads are cut short to fit a packet, which the debugger continues where it ended
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let (address, len) = parse_address_len(args)?;
+        let len = len.min(MAX_READ_LEN);
+        Some(
+            (0..len)
+                .map(|i| format!("{:02x}", self.device.read_byte(address.wrapping_add(i))))
+                .collect(),
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, len) = parse_address_len(range)?;
+        if data.len() != len as usize * 2 {
+            return None;
+        }
+        for i in 0..len {
+            let start = i as usize * 2;
+            let byte = u8::from_str_radix(data.get(start..start + 2)?, 16).ok()?;
+            self.device.write_byte(address.wrapping_add(i), byte);
+        }
+        Some(())
+    }
+
+    /// Handles `Z` and `z` packets: `type,address,kind`
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<()> {
+        let mut parts = args.splitn(3, ',');
+        let kind = parts.next()?;
+        let address = parse_hex(parts.next()?)?;
+        let len = parse_hex(parts.next()?)?;
+        let range = address..=address.wrapping_add(len.max(1) - 1);
+        let watch = match kind {
+            // Software and hardware breakpoints are the same to the emulator
+            "0" | "1" => {
+                match insert {
+                    true => self.device.add_breakpoint(address, None),
+                    false => self.device.remove_breakpoint(address, None),
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::ReadWrite,
+            _ => return None,
+        };
+        match insert {
+            true => self.device.add_watchpoint(range, watch),
+            false => self.device.remove_watchpoint(range),
+        }
+        Some(())
+    }
+
+    /// Handles `c` and `s` packets, which may start at a new address
+    fn resume(&mut self, args: &str, step: bool) -> String {
+        if let Some(pc) = parse_hex(args) {
+            let mut reg = self.device.cpu_state();
+            reg.pc = pc;
+            self.device.set_cpu_state(&reg);
+        }
+        if step {
+            return stop_reply(self.device.step_instruction());
+        }
+        loop {
+            match self.device.run_until_break(FRAME_TICKS) {
+                StopReason::TicksElapsed => {
+                    if self.interrupted() {
+                        return "S02".to_string();
+                    }
+                }
+                reason => return stop_reply(reason),
+            }
+        }
+    }
+
+    /// Checks without blocking whether the debugger asked to stop the target
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        match result {
+            Ok(0) => true,
+            Ok(_) => byte[0] == INTERRUPT,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint {
+            address, access, ..
+        } => {
+            let kind = match access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+            };
+            format!("T05{}:{:x};", kind, address)
+        }
+        _ => "S05".to_string(),
+    }
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_le(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn parse_le(s: &str) -> Option<u16> {
+    let low = u8::from_str_radix(s.get(0..2)?, 16).ok()?;
+    let high = u8::from_str_radix(s.get(2..4)?, 16).ok()?;
+    Some(u16::from_le_bytes([low, high]))
+}
+
+fn parse_address_len(s: &str) -> Option<(u16, u16)> {
+    let (address, len) = s.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+fn registers_to_array(reg: &CpuState) -> [u16; REGISTER_COUNT] {
+    [reg.af(), reg.bc(), reg.de(), reg.hl(), reg.sp, reg.pc]
+}
+
+fn array_to_registers(reg: &mut CpuState, values: [u16; REGISTER_COUNT]) {
+    reg.set_af(values[0]);
+    reg.set_bc(values[1]);
+    reg.set_de(values[2]);
+    reg.set_hl(values[3]);
+    reg.sp = values[4];
+    reg.pc = values[5];
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum_of, GdbStub, MAX_READ_LEN, PACKET_SIZE};
+    use crate::device::Device;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            while reply.last() != Some(&b'#') {
+                self.0.read_exact(&mut byte).unwrap();
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            self.0.write_all(b"+").unwrap();
+
+            let reply = String::from_utf8(reply).unwrap();
+            let start = reply.find('$').unwrap();
+            assert!(reply[..start].chars().all(|c| c == '+'));
+            reply[start + 1..reply.len() - 1].to_string()
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut rom = vec![0; 0x8000];
+        // CALL 0x0200; JR -2
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        // LD A,0x42; LD (0xC001),A; RET
+        rom[0x200..0x206].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x01, 0xC0, 0xC9]);
+
+        let stub = GdbStub::bind(0).unwrap();
+        let port = stub.port().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut device = Device::builder(rom)
+                .skip_checksum(true)
+                .classic_mode(true)
+                .build()
+                .unwrap();
+            stub.serve(&mut device).unwrap();
+        });
+
+        let mut client = Client(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        assert_eq!(client.send("?"), "S05");
+        assert_eq!(client.send("g"), "b0011300d8004d01feff0001");
+        assert_eq!(client.send("m100,3"), "cd0002");
+        assert_eq!(client.send("Z0,202,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p5"), "0202");
+        assert_eq!(client.send("z0,202,1"), "OK");
+        assert_eq!(client.send("Z2,c001,1"), "OK");
+        assert_eq!(client.send("c"), "T05watch:c001;");
+        assert_eq!(client.send("mc001,1"), "42");
+        assert_eq!(client.send("P0=00ff"), "OK");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("g"), "00ff1300d8004d01feff0301");
+        assert_eq!(client.send("Mc000,2:1234"), "OK");
+        assert_eq!(client.send("mc000,2"), "1234");
+        let reply = client.send("m0,ffff");
+        assert_eq!(reply.len(), MAX_READ_LEN as usize * 2);
+        assert!(reply.len() + 4 <= PACKET_SIZE);
+        assert_eq!(client.send("D"), "OK");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+//! Renders the contents of VRAM and OAM for debugging tools
+
+use super::GPU;
+use crate::gbmode::GbMode;
+
+/// Tiles per row of a bank in the tile sheet
+const TILES_PER_ROW: usize = 16;
+const TILES_PER_BANK: usize = 384;
+/// Sprites per row in the sprite sheet
+const SPRITES_PER_ROW: usize = 8;
+const SPRITE_COUNT: usize = 40;
+
+const SHADES: [u8; 4] = [255, 192, 96, 0];
+const VIEWPORT_COLOR: [u8; 3] = [255, 0, 0];
+
+/// An RGB image with 3 bytes per pixel, stored row by row
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DebugImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl DebugImage {
+    fn new(width: usize, height: usize) -> DebugImage {
+        DebugImage {
+            width,
+            height,
+            data: vec![255; width * height * 3],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let index = (y * self.width + x) * 3;
+        self.data[index..index + 3].copy_from_slice(&color);
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let index = (y * self.width + x) * 3;
+        [self.data[index], self.data[index + 1], self.data[index + 2]]
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum TileMap {
+    Map9800,
+    Map9C00,
+}
+
+impl TileMap {
+    fn base(self) -> u16 {
+        match self {
+            TileMap::Map9800 => 0x9800,
+            TileMap::Map9C00 => 0x9C00,
+        }
+    }
+}
+
+/// A decoded entry of the sprite attribute table
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct SpriteInfo {
+    /// Position in OAM, from 0 to 39
+    pub index: u8,
+    /// Screen position of the top left corner
+    pub x: i32,
+    pub y: i32,
+    pub tile: u8,
+    /// The raw attribute byte
+    pub flags: u8,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    pub behind_bg: bool,
+    /// OBP0 or OBP1 in classic mode, or one of the eight CGB palettes
+    pub palette: u8,
+    pub vram_bank: u8,
+}
+
+impl GPU {
+    /// Color number of a pixel of the tile at `offset` from the start of `bank`
+    fn tile_color(&self, bank: usize, offset: usize, x: usize, y: usize) -> usize {
+        let address = bank * 0x2000 + offset + y * 2;
+        let bit = 7 - x;
+        ((self.vram[address] >> bit) & 1) as usize
+            | ((((self.vram[address + 1] >> bit) & 1) as usize) << 1)
+    }
+
+    fn bg_color(&self, palette: usize, colnr: usize) -> [u8; 3] {
+        if self.gbmode == GbMode::Color {
+            self.lcd_rgb(self.cbgpal[palette][colnr])
+        } else {
+            [self.palb[colnr]; 3]
+        }
+    }
+
+    fn sprite_color(&self, palette: usize, colnr: usize) -> [u8; 3] {
+        if self.gbmode == GbMode::Color {
+            self.lcd_rgb(self.csprit[palette][colnr])
+        } else if palette == 1 {
+            [self.pal1[colnr]; 3]
+        } else {
+            [self.pal0[colnr]; 3]
+        }
+    }
+
+    /// Renders the tile data of both VRAM banks next to each other in shades of grey. Each bank
+    /// is 16 tiles wide and 24 tiles high.
+    pub fn render_tiles(&self) -> DebugImage {
+        let rows = TILES_PER_BANK / TILES_PER_ROW;
+        let mut image = DebugImage::new(2 * TILES_PER_ROW * 8, rows * 8);
+        for bank in 0..2 {
+            for tile in 0..TILES_PER_BANK {
+                let tilex = bank * TILES_PER_ROW + tile % TILES_PER_ROW;
+                let tiley = tile / TILES_PER_ROW;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let shade = SHADES[self.tile_color(bank, tile * 16, x, y)];
+                        image.set(tilex * 8 + x, tiley * 8 + y, [shade; 3]);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// Renders a 256x256 tile map with the current tile data, palettes and CGB attributes. When
+    /// the map is used for the background, the visible area is outlined.
+    pub fn render_tilemap(&self, map: TileMap) -> DebugImage {
+        let base = (map.base() - 0x8000) as usize;
+        let mut image = DebugImage::new(256, 256);
+        for tiley in 0..32 {
+            for tilex in 0..32 {
+                let index = base + tiley * 32 + tilex;
+                let tilenr = self.vram[index];
+                let flags = match self.gbmode {
+                    GbMode::Color => self.vram[0x2000 + index] as usize,
+                    _ => 0,
+                };
+                let offset = if self.tilebase == 0x8000 {
+                    tilenr as usize * 16
+                } else {
+                    (0x1000 + tilenr as i8 as isize * 16) as usize
+                };
+                let bank = (flags >> 3) & 1;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let pixelx = if flags & (1 << 5) != 0 { 7 - x } else { x };
+                        let pixely = if flags & (1 << 6) != 0 { 7 - y } else { y };
+                        let colnr = self.tile_color(bank, offset, pixelx, pixely);
+                        image.set(
+                            tilex * 8 + x,
+                            tiley * 8 + y,
+                            self.bg_color(flags & 7, colnr),
+                        );
+                    }
+                }
+            }
+        }
+        if map.base() == self.bg_tilemap {
+            self.outline_viewport(&mut image);
+        }
+        image
+    }
+
+    fn outline_viewport(&self, image: &mut DebugImage) {
+        let (left, top) = (self.scx as usize, self.scy as usize);
+        let right = (left + super::SCREEN_W - 1) & 0xFF;
+        let bottom = (top + super::SCREEN_H - 1) & 0xFF;
+        for i in 0..super::SCREEN_W {
+            image.set((left + i) & 0xFF, top, VIEWPORT_COLOR);
+            image.set((left + i) & 0xFF, bottom, VIEWPORT_COLOR);
+        }
+        for i in 0..super::SCREEN_H {
+            image.set(left, (top + i) & 0xFF, VIEWPORT_COLOR);
+            image.set(right, (top + i) & 0xFF, VIEWPORT_COLOR);
+        }
+    }
+
+    /// Decodes all 40 entries of the sprite attribute table
+    pub fn oam_entries(&self) -> Vec<SpriteInfo> {
+        let color = self.gbmode == GbMode::Color;
+        self.voam
+            .chunks(4)
+            .enumerate()
+            .map(|(index, entry)| {
+                let flags = entry[3];
+                SpriteInfo {
+                    index: index as u8,
+                    x: entry[1] as i32 - 8,
+                    y: entry[0] as i32 - 16,
+                    tile: entry[2],
+                    flags,
+                    x_flip: flags & (1 << 5) != 0,
+                    y_flip: flags & (1 << 6) != 0,
+                    behind_bg: flags & (1 << 7) != 0,
+                    palette: if color {
+                        flags & 0x07
+                    } else {
+                        (flags >> 4) & 1
+                    },
+                    vram_bank: if color { (flags >> 3) & 1 } else { 0 },
+                }
+            })
+            .collect()
+    }
+
+    /// Renders every sprite with its palette and flips in OAM order, 8 sprites per row.
+    /// Transparent pixels are white.
+    pub fn render_sprites(&self) -> DebugImage {
+        let height = self.sprite_size as usize;
+        let mut image =
+            DebugImage::new(SPRITES_PER_ROW * 8, SPRITE_COUNT / SPRITES_PER_ROW * height);
+        for sprite in self.oam_entries() {
+            let left = sprite.index as usize % SPRITES_PER_ROW * 8;
+            let top = sprite.index as usize / SPRITES_PER_ROW * height;
+            let tile = match height {
+                16 => sprite.tile & 0xFE,
+                _ => sprite.tile,
+            } as usize;
+            for y in 0..height {
+                for x in 0..8 {
+                    let pixelx = if sprite.x_flip { 7 - x } else { x };
+                    let pixely = if sprite.y_flip { height - 1 - y } else { y };
+                    let colnr =
+                        self.tile_color(sprite.vram_bank as usize, tile * 16, pixelx, pixely);
+                    if colnr != 0 {
+                        let color = self.sprite_color(sprite.palette as usize, colnr);
+                        image.set(left + x, top + y, color);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TileMap, VIEWPORT_COLOR};
+    use crate::gpu::GPU;
+
+    #[test]
+    fn render_vram() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF47, 0b11100100);
+        gpu.wb(0xFF48, 0b00011011);
+        // Tile 1: the top row has colors 0, 1, 2, 3 in the first four pixels
+        gpu.wb(0x8010, 0b01010000);
+        gpu.wb(0x8011, 0b00110000);
+        // Place tile 1 at the second position of the 0x9800 map
+        gpu.wb(0x9801, 1);
+        // Sprite 2 uses tile 1, flipped horizontally
+        gpu.wb(0xFE08, 20);
+        gpu.wb(0xFE09, 10);
+        gpu.wb(0xFE0A, 1);
+        gpu.wb(0xFE0B, 0x20);
+
+        let tiles = gpu.render_tiles();
+        assert_eq!((tiles.width, tiles.height), (256, 192));
+        let row: Vec<u8> = (8..12).map(|x| tiles.pixel(x, 0)[0]).collect();
+        assert_eq!(row, [255, 192, 96, 0]);
+
+        let map = gpu.render_tilemap(TileMap::Map9800);
+        assert_eq!(map.pixel(9, 0), [192; 3]);
+        assert_eq!(map.pixel(11, 0), [0; 3]);
+        assert_ne!(map.pixel(0, 0), VIEWPORT_COLOR);
+        // The background uses 0x9800 after LCDC is written, and the viewport wraps around
+        gpu.wb(0xFF40, 0x81);
+        gpu.wb(0xFF43, 200);
+        let map = gpu.render_tilemap(TileMap::Map9800);
+        assert_eq!(map.pixel(200, 0), VIEWPORT_COLOR);
+        assert_eq!(map.pixel(103, 143), VIEWPORT_COLOR);
+        assert_eq!(map.pixel(103, 144), [255; 3]);
+
+        let sprites = gpu.oam_entries();
+        assert_eq!(sprites.len(), 40);
+        assert_eq!((sprites[2].x, sprites[2].y, sprites[2].tile), (2, 4, 1));
+        assert!(sprites[2].x_flip && !sprites[2].y_flip);
+        let image = gpu.render_sprites();
+        // OBP0 inverts the shades; color 0 is transparent
+        assert_eq!(image.pixel(23, 0), [255; 3]);
+        assert_eq!(image.pixel(20, 0), [255; 3]);
+        assert_eq!(image.pixel(21, 0), [192; 3]);
+    }
+}
@@ -1,9 +1,15 @@
-use crate::gbmode::GbMode;
+use crate::gbmode::{GbMode, HardwareModel};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
+pub use self::debug::{DebugImage, SpriteInfo, TileMap};
+
+mod debug;
+
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
+/// Size of the CGB background or sprite palette memory
+pub const PALETTE_SIZE: usize = 0x40;
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
@@ -44,8 +50,7 @@ pub struct GPU {
     palb: [u8; 4],
     pal0: [u8; 4],
     pal1: [u8; 4],
-    #[serde(with = "serde_arrays")]
-    vram: [u8; VRAM_SIZE],
+    vram: Vec<u8>,
     #[serde(with = "serde_arrays")]
     voam: [u8; VOAM_SIZE],
     cbgpal_inc: bool,
@@ -63,6 +68,11 @@ pub struct GPU {
     pub gbmode: GbMode,
     hblanking: bool,
     first_frame: bool,
+    /// Classic games use the CGB palettes chosen by the CGB boot ROM
+    #[serde(default)]
+    pub compat_palettes: bool,
+    #[serde(default)]
+    pub model: HardwareModel,
 }
 
 impl GPU {
@@ -96,7 +106,7 @@ impl GPU {
             palb: [0; 4],
             pal0: [0; 4],
             pal1: [0; 4],
-            vram: [0; VRAM_SIZE],
+            vram: vec![0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: vec![0; SCREEN_W * SCREEN_H * 3],
             bgprio: [PrioType::Normal; SCREEN_W],
@@ -112,6 +122,8 @@ impl GPU {
             vrambank: 0,
             hblanking: false,
             first_frame: false,
+            compat_palettes: false,
+            model: HardwareModel::Dmg,
         }
     }
 
@@ -239,27 +251,9 @@ impl GPU {
             0xFF4F..=0xFF6B if self.gbmode != GbMode::Color => 0xFF,
             0xFF4F => self.vrambank as u8 | 0xFE,
             0xFF68 => 0x40 | self.cbgpal_ind | (if self.cbgpal_inc { 0x80 } else { 0 }),
-            0xFF69 => {
-                let palnum = (self.cbgpal_ind >> 3) as usize;
-                let colnum = ((self.cbgpal_ind >> 1) & 0x3) as usize;
-                if self.cbgpal_ind & 0x01 == 0x00 {
-                    self.cbgpal[palnum][colnum][0] | ((self.cbgpal[palnum][colnum][1] & 0x07) << 5)
-                } else {
-                    ((self.cbgpal[palnum][colnum][1] & 0x18) >> 3)
-                        | (self.cbgpal[palnum][colnum][2] << 2)
-                }
-            }
+            0xFF69 => palette_byte(&self.cbgpal, self.cbgpal_ind),
             0xFF6A => 0x40 | self.csprit_ind | (if self.csprit_inc { 0x80 } else { 0 }),
-            0xFF6B => {
-                let palnum = (self.csprit_ind >> 3) as usize;
-                let colnum = ((self.csprit_ind >> 1) & 0x3) as usize;
-                if self.csprit_ind & 0x01 == 0x00 {
-                    self.csprit[palnum][colnum][0] | ((self.csprit[palnum][colnum][1] & 0x07) << 5)
-                } else {
-                    ((self.csprit[palnum][colnum][1] & 0x18) >> 3)
-                        | (self.csprit[palnum][colnum][2] << 2)
-                }
-            }
+            0xFF6B => palette_byte(&self.csprit, self.csprit_ind),
             _ => 0xFF,
         }
     }
@@ -341,17 +335,7 @@ impl GPU {
                 self.cbgpal_inc = v & 0x80 == 0x80;
             }
             0xFF69 => {
-                let palnum = (self.cbgpal_ind >> 3) as usize;
-                let colnum = ((self.cbgpal_ind >> 1) & 0x03) as usize;
-                if self.cbgpal_ind & 0x01 == 0x00 {
-                    self.cbgpal[palnum][colnum][0] = v & 0x1F;
-                    self.cbgpal[palnum][colnum][1] =
-                        (self.cbgpal[palnum][colnum][1] & 0x18) | (v >> 5);
-                } else {
-                    self.cbgpal[palnum][colnum][1] =
-                        (self.cbgpal[palnum][colnum][1] & 0x07) | ((v & 0x3) << 3);
-                    self.cbgpal[palnum][colnum][2] = (v >> 2) & 0x1F;
-                }
+                set_palette_byte(&mut self.cbgpal, self.cbgpal_ind, v);
                 if self.cbgpal_inc {
                     self.cbgpal_ind = (self.cbgpal_ind + 1) & 0x3F;
                 };
@@ -361,17 +345,7 @@ impl GPU {
                 self.csprit_inc = v & 0x80 == 0x80;
             }
             0xFF6B => {
-                let palnum = (self.csprit_ind >> 3) as usize;
-                let colnum = ((self.csprit_ind >> 1) & 0x03) as usize;
-                if self.csprit_ind & 0x01 == 0x00 {
-                    self.csprit[palnum][colnum][0] = v & 0x1F;
-                    self.csprit[palnum][colnum][1] =
-                        (self.csprit[palnum][colnum][1] & 0x18) | (v >> 5);
-                } else {
-                    self.csprit[palnum][colnum][1] =
-                        (self.csprit[palnum][colnum][1] & 0x07) | ((v & 0x3) << 3);
-                    self.csprit[palnum][colnum][2] = (v >> 2) & 0x1F;
-                }
+                set_palette_byte(&mut self.csprit, self.csprit_ind, v);
                 if self.csprit_inc {
                     self.csprit_ind = (self.csprit_ind + 1) & 0x3F;
                 };
@@ -425,18 +399,9 @@ impl GPU {
     }
 
     fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
         let baseidx = self.line as usize * SCREEN_W * 3 + x * 3;
-
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
-
-        self.data[baseidx + 0] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+        let color = self.lcd_rgb([r, g, b]);
+        self.data[baseidx..baseidx + 3].copy_from_slice(&color);
     }
 
     fn draw_bg(&mut self) {
@@ -534,6 +499,9 @@ impl GPU {
                 let g = self.cbgpal[palnr][colnr][1];
                 let b = self.cbgpal[palnr][colnr][2];
                 self.setrgb(x as usize, r, g, b);
+            } else if self.compat_palettes {
+                let [r, g, b] = self.cbgpal[0][(self.palbr >> (colnr * 2)) as usize & 3];
+                self.setrgb(x, r, g, b);
             } else {
                 let color = self.palb[colnr];
                 self.setcolor(x, color);
@@ -627,6 +595,16 @@ impl GPU {
                     if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0 {
                         continue 'xloop;
                     }
+                    if self.compat_palettes {
+                        let (palnr, palr) = if usepal1 {
+                            (1, self.pal1r)
+                        } else {
+                            (0, self.pal0r)
+                        };
+                        let [r, g, b] = self.csprit[palnr][(palr >> (colnr * 2)) as usize & 3];
+                        self.setrgb((spritex + x) as usize, r, g, b);
+                        continue 'xloop;
+                    }
                     let color = if usepal1 {
                         self.pal1[colnr]
                     } else {
@@ -638,11 +616,111 @@ impl GPU {
         }
     }
 
+    /// Tile data of the first 256 background tiles on screen, 20 tiles per row. This is how the
+    /// Super Gameboy receives transfers from the Gameboy.
+    pub fn screen_tile_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(256 * 16);
+        for i in 0..256 {
+            let tilenr = self.rbvram0(self.bg_tilemap + (i / 20) * 32 + i % 20);
+            let tileaddress = self.tilebase
+                + (if self.tilebase == 0x8000 {
+                    tilenr as u16
+                } else {
+                    (tilenr as i8 as i16 + 128) as u16
+                }) * 16;
+            for offset in 0..16 {
+                data.push(self.rbvram0(tileaddress + offset));
+            }
+        }
+        data
+    }
+
+    /// Converts an RGB555 color to the color shown by the LCD of the model
+    fn lcd_rgb(&self, color: [u8; 3]) -> [u8; 3] {
+        match self.model {
+            // The AGB screen shows the colors without the tint of the CGB screen
+            HardwareModel::Agb => color.map(|c| (c << 3) | (c >> 2)),
+            _ => cgb_rgb(color),
+        }
+    }
+
+    pub fn is_vblank(&self) -> bool {
+        self.mode == 1
+    }
+
+    /// Both VRAM banks, regardless of the selected bank
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.voam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8] {
+        &mut self.voam
+    }
+
+    /// Reads the CGB palette memory as it is exposed through 0xFF69 or 0xFF6B
+    pub fn palette_byte(&self, sprite: bool, index: u8) -> u8 {
+        match sprite {
+            false => palette_byte(&self.cbgpal, index),
+            true => palette_byte(&self.csprit, index),
+        }
+    }
+
+    pub fn set_palette_byte(&mut self, sprite: bool, index: u8, v: u8) {
+        match sprite {
+            false => set_palette_byte(&mut self.cbgpal, index, v),
+            true => set_palette_byte(&mut self.csprit, index, v),
+        }
+    }
+
     pub fn may_hdma(&self) -> bool {
         return self.hblanking;
     }
 }
 
+/// Gameboy Color RGB correction, taken from the Gambatte emulator. The components of `color`
+/// are between 0 and 1F.
+fn cgb_rgb(color: [u8; 3]) -> [u8; 3] {
+    let r = color[0] as u32;
+    let g = color[1] as u32;
+    let b = color[2] as u32;
+    [
+        ((r * 13 + g * 2 + b) >> 1) as u8,
+        ((g * 3 + b) << 1) as u8,
+        ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+    ]
+}
+
+/// Encodes a color component of the palette memory in the RGB555 format of the CGB
+fn palette_byte(pal: &[[[u8; 3]; 4]; 8], index: u8) -> u8 {
+    let palnum = ((index >> 3) & 0x7) as usize;
+    let colnum = ((index >> 1) & 0x3) as usize;
+    if index & 0x01 == 0x00 {
+        pal[palnum][colnum][0] | ((pal[palnum][colnum][1] & 0x07) << 5)
+    } else {
+        ((pal[palnum][colnum][1] & 0x18) >> 3) | (pal[palnum][colnum][2] << 2)
+    }
+}
+
+fn set_palette_byte(pal: &mut [[[u8; 3]; 4]; 8], index: u8, v: u8) {
+    let palnum = ((index >> 3) & 0x7) as usize;
+    let colnum = ((index >> 1) & 0x3) as usize;
+    if index & 0x01 == 0x00 {
+        pal[palnum][colnum][0] = v & 0x1F;
+        pal[palnum][colnum][1] = (pal[palnum][colnum][1] & 0x18) | (v >> 5);
+    } else {
+        pal[palnum][colnum][1] = (pal[palnum][colnum][1] & 0x07) | ((v & 0x3) << 3);
+        pal[palnum][colnum][2] = (v >> 2) & 0x1F;
+    }
+}
+
 // Functions to determine the order of sprites. Input is a tuple x-coord, OAM position
 // These function ensures that sprites with a higher priority are 'larger'
 fn dmg_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
@@ -20,6 +20,22 @@ pub enum KeypadKey {
     Start,
 }
 
+impl KeypadKey {
+    /// The bit of this key in a mask of pressed keys
+    pub(crate) fn mask(self) -> u8 {
+        match self {
+            KeypadKey::Right => 1 << 0,
+            KeypadKey::Left => 1 << 1,
+            KeypadKey::Up => 1 << 2,
+            KeypadKey::Down => 1 << 3,
+            KeypadKey::A => 1 << 4,
+            KeypadKey::B => 1 << 5,
+            KeypadKey::Select => 1 << 6,
+            KeypadKey::Start => 1 << 7,
+        }
+    }
+}
+
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
@@ -57,6 +73,17 @@ impl Keypad {
         self.data = (self.data & 0xF0) | new_values;
     }
 
+    /// Bitmask of the pressed keys, in the order of `KeypadKey`
+    pub fn pressed(&self) -> u8 {
+        (!self.row0 & 0x0F) | ((!self.row1 & 0x0F) << 4)
+    }
+
+    pub fn set_pressed(&mut self, pressed: u8) {
+        self.row0 = !pressed & 0x0F;
+        self.row1 = !(pressed >> 4) & 0x0F;
+        self.update();
+    }
+
     pub fn keydown(&mut self, key: KeypadKey) {
         match key {
             KeypadKey::Right => self.row0 &= !(1 << 0),
@@ -119,6 +146,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn pressed_mask() {
+        let mut keypad = super::Keypad::new();
+        keypad.keydown(KeypadKey::Left);
+        keypad.keydown(KeypadKey::Start);
+        assert_eq!(keypad.pressed(), 0x82);
+
+        let mut other = super::Keypad::new();
+        other.set_pressed(0x82);
+        other.wb(0x10);
+        assert_eq!(other.rb(), 0xD7);
+        other.wb(0x20);
+        assert_eq!(other.rb(), 0xED);
+    }
+
     #[test]
     fn keys_direction() {
         let mut keypad = super::Keypad::new();
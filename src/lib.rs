@@ -1,23 +1,43 @@
 #![crate_name = "rboy"]
 #![crate_type = "lib"]
 
-pub use crate::gpu::{SCREEN_H, SCREEN_W};
+pub use crate::cheats::{Cheat, CheatKind, SharkBank};
+pub use crate::cpu::CpuState;
+pub use crate::debugger::{Access, StopReason, WatchKind};
+pub use crate::error::Error;
+pub use crate::gbmode::HardwareModel;
+pub use crate::gpu::{DebugImage, SpriteInfo, TileMap, SCREEN_H, SCREEN_W};
 pub use crate::keypad::KeypadKey;
+pub use crate::mbc::{
+    Clock, EmulatedClock, ImageSource, InfraredCallback, InfraredLink, SaveRamSink, StaticImage,
+    TestPattern, ToneCallback, WallClock, CAMERA_H, CAMERA_W,
+};
+pub use crate::mmu::MemoryDomain;
+pub use crate::movie::Movie;
 pub use crate::serial::SerialCallback;
+pub use crate::sgb::{SGB_SCREEN_H, SGB_SCREEN_W};
 pub use crate::sound::AudioPlayer;
+pub use crate::trace::{FileTrace, TraceCallback, TraceFilter};
 
 pub mod device;
+pub mod disasm;
+pub mod gdb;
 
+mod cheats;
 mod cpu;
+mod debugger;
+mod error;
 mod gbmode;
 mod gpu;
 mod keypad;
 mod mbc;
 mod mmu;
+mod movie;
 mod printer;
 mod register;
+mod rewind;
 mod serial;
+mod sgb;
 mod sound;
 mod timer;
-
-pub type StrResult<T> = Result<T, &'static str>;
+mod trace;
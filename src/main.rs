@@ -11,6 +11,11 @@ use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
 
 const EXITCODE_SUCCESS: i32 = 0;
 const EXITCODE_CPULOADFAILS: i32 = 2;
+const EXITCODE_GDBFAILS: i32 = 3;
+
+// Keep a snapshot every other frame, for up to 20 seconds of rewinding
+const REWIND_INTERVAL: u32 = 2;
+const REWIND_CAPACITY: usize = 600;
 
 #[derive(Default)]
 struct RenderOptions {
@@ -22,6 +27,9 @@ enum GBEvent {
     KeyDown(rboy::KeypadKey),
     SpeedUp,
     SpeedDown,
+    RewindStart,
+    RewindStop,
+    Tilt(f32, f32),
 }
 
 #[cfg(target_os = "windows")]
@@ -67,6 +75,31 @@ fn parse_scale_var(arg: &str) -> Result<u32, ArgParseError> {
     }
 }
 
+/// Parses an inclusive range of hexadecimal addresses, like `0150-01FF`
+fn parse_trace_range(arg: &str) -> Result<std::ops::RangeInclusive<u16>, ArgParseError> {
+    let parse = |s: &str| {
+        u16::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|e| ArgParseError::new(format!("Could not parse address {}: {}", s, e)))
+    };
+    match arg.split_once('-') {
+        Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+        None => Err(ArgParseError::new("Range must be given as START-END")),
+    }
+}
+
+fn parse_model(arg: &str) -> Result<rboy::HardwareModel, ArgParseError> {
+    match arg.to_ascii_lowercase().as_str() {
+        "dmg" => Ok(rboy::HardwareModel::Dmg),
+        "mgb" => Ok(rboy::HardwareModel::Mgb),
+        "sgb" => Ok(rboy::HardwareModel::Sgb),
+        "cgb" => Ok(rboy::HardwareModel::Cgb),
+        "agb" => Ok(rboy::HardwareModel::Agb),
+        _ => Err(ArgParseError::new(
+            "Model must be one of dmg, mgb, sgb, cgb or agb",
+        )),
+    }
+}
+
 fn main() {
     let exit_status = real_main();
     if exit_status != EXITCODE_SUCCESS {
@@ -105,6 +138,13 @@ fn real_main() -> i32 {
                 .long("classic")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("model")
+                .help("Emulates the specified hardware: dmg, mgb, sgb, cgb or agb. Default: cgb")
+                .long("model")
+                .conflicts_with("classic")
+                .value_parser(parse_model),
+        )
         .arg(
             clap::Arg::new("scale")
                 .help("Sets the scale of the interface. Default: 2")
@@ -136,6 +176,59 @@ fn real_main() -> i32 {
                 .help("Starts the emulator from a saved state file at the specified path")
                 .long("load-state"),
         )
+        .arg(
+            clap::Arg::new("record")
+                .help("Records the keypad input to a movie file at the specified path")
+                .long("record")
+                .conflicts_with("play"),
+        )
+        .arg(
+            clap::Arg::new("play")
+                .help("Plays back the keypad input from a movie file at the specified path")
+                .long("play")
+                .conflicts_with("state-path"),
+        )
+        .arg(
+            clap::Arg::new("gdb")
+                .help("Runs without a window, controlled by a GDB debugger on the specified localhost port")
+                .long("gdb")
+                .conflicts_with_all(["test-mode", "record"])
+                .value_parser(clap::value_parser!(u16)),
+        )
+        .arg(
+            clap::Arg::new("trace")
+                .help("Writes a Gameboy Doctor trace of every instruction to the specified path")
+                .long("trace"),
+        )
+        .arg(
+            clap::Arg::new("trace-pc")
+                .help("Only traces instructions in a range of addresses, like 0150-01FF")
+                .long("trace-pc")
+                .requires("trace")
+                .value_parser(parse_trace_range),
+        )
+        .arg(
+            clap::Arg::new("trace-bank")
+                .help("Only traces instructions in the specified ROM bank")
+                .long("trace-bank")
+                .requires("trace")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            clap::Arg::new("boot-rom")
+                .help("Runs the specified DMG or CGB boot ROM before the game")
+                .long("boot-rom"),
+        )
+        .arg(
+            clap::Arg::new("cheats")
+                .help("Loads Game Genie and GameShark codes from the specified cheat file instead of the .cht file next to the ROM")
+                .long("cheats"),
+        )
+        .arg(
+            clap::Arg::new("camera")
+                .help("Shows the specified binary PGM image to the Pocket Camera instead of a test pattern")
+                .long("camera"),
+        )
         .get_matches();
 
     let test_mode = matches.get_one::<bool>("test-mode").copied().unwrap();
@@ -145,36 +238,132 @@ fn real_main() -> i32 {
     let opt_serial = matches.get_one::<bool>("serial").copied().unwrap();
     let opt_printer = matches.get_one::<bool>("printer").copied().unwrap();
     let opt_classic = matches.get_one::<bool>("classic").copied().unwrap();
+    let model = match matches.get_one::<rboy::HardwareModel>("model") {
+        Some(model) => *model,
+        None if opt_classic => rboy::HardwareModel::Dmg,
+        None => rboy::HardwareModel::Cgb,
+    };
     let opt_audio = matches.get_one::<bool>("audio").copied().unwrap();
     let opt_skip_checksum = matches.get_one::<bool>("skip-checksum").copied().unwrap();
+    let opt_record = matches.get_one::<String>("record").cloned();
+    let opt_play = matches.get_one::<String>("play");
     let filename = matches.get_one::<String>("filename").unwrap();
     let scale = matches.get_one::<u32>("scale").copied().unwrap_or(2);
+    let opt_gdb = matches.get_one::<u16>("gdb").copied();
+    let opt_cheats = matches.get_one::<String>("cheats");
+    let opt_boot_rom = matches.get_one::<String>("boot-rom");
+    let opt_camera = matches.get_one::<String>("camera");
+    let opt_trace = matches.get_one::<String>("trace").map(|path| {
+        let filter = rboy::TraceFilter {
+            pc_range: matches
+                .get_one::<std::ops::RangeInclusive<u16>>("trace-pc")
+                .cloned(),
+            bank: matches.get_one::<usize>("trace-bank").copied(),
+        };
+        (path.as_str(), filter)
+    });
 
     if test_mode {
-        return run_test_mode(filename, opt_classic, opt_skip_checksum);
+        return run_test_mode(filename, model, opt_skip_checksum, opt_trace);
     }
 
     let mut is_new_start = true;
-    let cpu = opt_reload
+    let cpu = match opt_reload
         .as_ref()
         .filter(|path| std::path::Path::new(path).exists())
-        .and_then(|path| {
+    {
+        Some(path) => {
             is_new_start = false;
-            Device::load_state(path)
-        })
-        .or_else(|| construct_cpu(filename, opt_classic, opt_skip_checksum, opt_reload.clone()));
+            match Device::load_state(filename, path) {
+                Ok(cpu) => Some(cpu),
+                Err(message) => {
+                    warn(message);
+                    None
+                }
+            }
+        }
+        None => construct_cpu(filename, model, opt_skip_checksum, opt_reload.clone()),
+    };
 
     if cpu.is_none() {
         return EXITCODE_CPULOADFAILS;
     }
     let mut cpu = cpu.unwrap();
 
+    if let Some(path) = opt_boot_rom.filter(|_| is_new_start) {
+        let result = std::fs::read(path)
+            .map_err(|e| rboy::Error::Io {
+                path: path.into(),
+                source: e,
+            })
+            .and_then(|data| cpu.load_boot_rom(data));
+        if let Err(message) = result {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
+    if let Some(path) = opt_camera {
+        match rboy::StaticImage::load(path) {
+            Ok(image) => cpu.set_image_source(Box::new(image)),
+            Err(message) => {
+                warn(message);
+                return EXITCODE_CPULOADFAILS;
+            }
+        }
+    }
+
+    if opt_record.is_some() || opt_play.is_some() {
+        // Movies can only be replayed when the RTC does not depend on the host clock
+        cpu.set_rtc_clock(Box::new(rboy::EmulatedClock::new(0)));
+    }
+    if let Some(path) = opt_play {
+        let result = match std::fs::read(path) {
+            Ok(data) => rboy::Movie::from_bytes(&data).and_then(|movie| cpu.play_movie(movie)),
+            Err(e) => Err(rboy::Error::Io {
+                path: path.into(),
+                source: e,
+            }),
+        };
+        if let Err(message) = result {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if opt_record.is_some() {
+        if let Err(message) = cpu.start_movie_recording(!is_new_start) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
+    if let Some((path, filter)) = opt_trace {
+        if !start_trace(&mut cpu, path, filter) {
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
+    let cheats_path = match opt_cheats {
+        Some(path) => Some(std::path::PathBuf::from(path)),
+        None => Some(std::path::Path::new(filename).with_extension("cht")).filter(|p| p.exists()),
+    };
+    if let Some(path) = cheats_path {
+        if let Err(message) = cpu.load_cheats(path) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
     if opt_printer {
         cpu.attach_printer();
     } else {
         cpu.set_stdout(opt_serial);
     }
 
+    if let Some(port) = opt_gdb {
+        return run_gdb(&mut cpu, port);
+    }
+
     let mut cpal_audio_stream = None;
     if opt_audio {
         let player = CpalPlayer::get();
@@ -190,6 +379,7 @@ fn real_main() -> i32 {
         }
     }
     let romname = cpu.romname();
+    let (screen_w, screen_h) = cpu.screen_size();
 
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
@@ -199,48 +389,100 @@ fn real_main() -> i32 {
     let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
         .set_window_builder(window_builder)
         .build(&event_loop);
-    set_window_size(&window, scale);
+    set_window_size(&window, screen_w, screen_h, scale);
 
     let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
         &display,
         glium::texture::UncompressedFloatFormat::U8U8U8,
         glium::texture::MipmapsOption::NoMipmap,
-        rboy::SCREEN_W as u32,
-        rboy::SCREEN_H as u32,
+        screen_w as u32,
+        screen_h as u32,
     )
     .unwrap();
 
     let mut renderoptions = <RenderOptions as Default>::default();
+    // Tilt from the I/J/K/L keys, and from the mouse position while the left button is held
+    let mut key_tilt = (0.0, 0.0);
+    let mut mouse_tilt = None;
+    let mut cursor_tilt = (0.0, 0.0);
 
-    let cputhread = thread::spawn(move || run_cpu(cpu, sender2, receiver1));
+    let cputhread = thread::spawn(move || run_cpu(cpu, sender2, receiver1, opt_record));
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     'evloop: loop {
         let timeout = Some(std::time::Duration::ZERO);
         let status = event_loop.pump_events(timeout, |ev, elwt| {
             use winit::event::ElementState::{Pressed, Released};
-            use winit::event::{Event, WindowEvent};
+            use winit::event::{Event, MouseButton, WindowEvent};
             use winit::keyboard::{Key, NamedKey};
 
             match ev {
                 Event::WindowEvent { event, .. } => match event {
                     WindowEvent::CloseRequested => elwt.exit(),
+                    WindowEvent::CursorMoved { position, .. } => {
+                        let size = window.inner_size();
+                        cursor_tilt = (
+                            (position.x / size.width as f64 * 2.0 - 1.0) as f32,
+                            (position.y / size.height as f64 * 2.0 - 1.0) as f32,
+                        );
+                        if mouse_tilt.is_some() {
+                            mouse_tilt = Some(cursor_tilt);
+                            let _ = sender1.send(GBEvent::Tilt(cursor_tilt.0, cursor_tilt.1));
+                        }
+                    }
+                    WindowEvent::MouseInput {
+                        state,
+                        button: MouseButton::Left,
+                        ..
+                    } => {
+                        mouse_tilt = match state {
+                            Pressed => Some(cursor_tilt),
+                            Released => None,
+                        };
+                        let (x, y) = mouse_tilt.unwrap_or(key_tilt);
+                        let _ = sender1.send(GBEvent::Tilt(x, y));
+                    }
                     WindowEvent::KeyboardInput {
                         event: keyevent, ..
                     } => match (keyevent.state, keyevent.logical_key.as_ref()) {
                         (Pressed, Key::Named(NamedKey::Escape)) => elwt.exit(),
-                        (Pressed, Key::Character("1")) => set_window_size(&window, 1),
-                        (Pressed, Key::Character("r" | "R")) => set_window_size(&window, scale),
+                        (Pressed, Key::Character("1")) => {
+                            set_window_size(&window, screen_w, screen_h, 1)
+                        }
+                        (Pressed, Key::Character("r" | "R")) => {
+                            set_window_size(&window, screen_w, screen_h, scale)
+                        }
                         (Pressed, Key::Named(NamedKey::Shift)) => {
                             let _ = sender1.send(GBEvent::SpeedUp);
                         }
                         (Released, Key::Named(NamedKey::Shift)) => {
                             let _ = sender1.send(GBEvent::SpeedDown);
                         }
+                        (Pressed, Key::Named(NamedKey::Backspace)) => {
+                            let _ = sender1.send(GBEvent::RewindStart);
+                        }
+                        (Released, Key::Named(NamedKey::Backspace)) => {
+                            let _ = sender1.send(GBEvent::RewindStop);
+                        }
                         (Pressed, Key::Character("t" | "T")) => {
                             renderoptions.linear_interpolation =
                                 !renderoptions.linear_interpolation;
                         }
+                        (
+                            state,
+                            Key::Character(c @ ("i" | "I" | "j" | "J" | "k" | "K" | "l" | "L")),
+                        ) => {
+                            let amount = if state == Pressed { 1.0 } else { 0.0 };
+                            match c {
+                                "i" | "I" => key_tilt.1 = -amount,
+                                "k" | "K" => key_tilt.1 = amount,
+                                "j" | "J" => key_tilt.0 = -amount,
+                                _ => key_tilt.0 = amount,
+                            }
+                            if mouse_tilt.is_none() {
+                                let _ = sender1.send(GBEvent::Tilt(key_tilt.0, key_tilt.1));
+                            }
+                        }
                         (Pressed, winitkey) => {
                             if let Some(key) = winit_to_keypad(winitkey) {
                                 let _ = sender1.send(GBEvent::KeyDown(key));
@@ -307,16 +549,16 @@ fn recalculate_screen<
 
     let rawimage2d = glium::texture::RawImage2d {
         data: std::borrow::Cow::Borrowed(datavec),
-        width: rboy::SCREEN_W as u32,
-        height: rboy::SCREEN_H as u32,
+        width: texture.width(),
+        height: texture.height(),
         format: glium::texture::ClientFormat::U8U8U8,
     };
     texture.write(
         glium::Rect {
             left: 0,
             bottom: 0,
-            width: rboy::SCREEN_W as u32,
-            height: rboy::SCREEN_H as u32,
+            width: texture.width(),
+            height: texture.height(),
         },
         rawimage2d,
     );
@@ -337,21 +579,17 @@ fn recalculate_screen<
     target.finish().unwrap();
 }
 
-fn warn(message: &str) {
+fn warn<T: std::fmt::Display>(message: T) {
     eprintln!("{}", message);
 }
 
 fn construct_cpu(
     filename: &str,
-    classic_mode: bool,
+    model: rboy::HardwareModel,
     skip_checksum: bool,
     reload_mode: Option<String>,
 ) -> Option<Box<Device>> {
-    let opt_c = match classic_mode {
-        true => Device::new(filename, skip_checksum, reload_mode),
-        false => Device::new_cgb(filename, skip_checksum, reload_mode),
-    };
-    let c = match opt_c {
+    let c = match Device::new_with_model(filename, skip_checksum, reload_mode, model) {
         Ok(cpu) => cpu,
         Err(message) => {
             warn(message);
@@ -362,21 +600,38 @@ fn construct_cpu(
     Some(Box::new(c))
 }
 
-fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
+fn run_cpu(
+    mut cpu: Box<Device>,
+    sender: SyncSender<Vec<u8>>,
+    receiver: Receiver<GBEvent>,
+    movie_path: Option<String>,
+) {
     let periodic = timer_periodic(16);
     let mut limit_speed = true;
+    let mut rewinding = false;
 
     let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
     let mut ticks = 0;
 
+    // Rewinding would break the input of a movie
+    if movie_path.is_none() && !cpu.is_playing_movie() {
+        cpu.enable_rewind(REWIND_INTERVAL, REWIND_CAPACITY);
+    }
+
     'outer: loop {
         while ticks < waitticks {
-            ticks += cpu.do_cycle();
-            if cpu.check_and_reset_gpu_updated() {
-                let data = cpu.get_gpu_data().to_vec();
-                if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
-                    break 'outer;
+            if rewinding {
+                if let Err(message) = cpu.rewind(REWIND_INTERVAL) {
+                    warn(message);
                 }
+                ticks += rboy::device::FRAME_TICKS;
+            } else {
+                let (_, frameticks) = cpu.run_frame();
+                ticks += frameticks;
+            }
+            let data = cpu.get_frame();
+            if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
+                break 'outer;
             }
         }
 
@@ -392,6 +647,9 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
                         limit_speed = true;
                         cpu.sync_audio();
                     }
+                    GBEvent::RewindStart => rewinding = true,
+                    GBEvent::RewindStop => rewinding = false,
+                    GBEvent::Tilt(x, y) => cpu.set_tilt(x, y),
                 },
                 Err(TryRecvError::Empty) => break 'recv,
                 Err(TryRecvError::Disconnected) => break 'outer,
@@ -402,6 +660,14 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
             let _ = periodic.recv();
         }
     }
+
+    if let Some(path) = movie_path {
+        if let Some(movie) = cpu.stop_movie() {
+            if let Err(e) = std::fs::write(&path, movie.to_bytes()) {
+                warn(format!("Could not write movie to {}: {}", path, e));
+            }
+        }
+    }
 }
 
 fn timer_periodic(ms: u64) -> Receiver<()> {
@@ -415,10 +681,10 @@ fn timer_periodic(ms: u64) -> Receiver<()> {
     rx
 }
 
-fn set_window_size(window: &winit::window::Window, scale: u32) {
+fn set_window_size(window: &winit::window::Window, width: usize, height: usize, scale: u32) {
     let _ = window.request_inner_size(winit::dpi::LogicalSize::<u32>::from((
-        rboy::SCREEN_W as u32 * scale,
-        rboy::SCREEN_H as u32 * scale,
+        width as u32 * scale,
+        height as u32 * scale,
     )));
 }
 
@@ -620,12 +886,40 @@ impl rboy::AudioPlayer for NullAudioPlayer {
     }
 }
 
-fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32 {
-    let opt_cpu = match classic_mode {
-        true => Device::new(filename, skip_checksum, None),
-        false => Device::new_cgb(filename, skip_checksum, None),
-    };
-    let mut cpu = match opt_cpu {
+fn run_gdb(cpu: &mut Device, port: u16) -> i32 {
+    let result = rboy::gdb::GdbStub::bind(port).and_then(|stub| {
+        eprintln!("Waiting for GDB on localhost:{}", port);
+        stub.serve(cpu)
+    });
+    match result {
+        Ok(()) => EXITCODE_SUCCESS,
+        Err(message) => {
+            warn(message);
+            EXITCODE_GDBFAILS
+        }
+    }
+}
+
+fn start_trace(cpu: &mut Device, path: &str, filter: rboy::TraceFilter) -> bool {
+    match rboy::FileTrace::create(path) {
+        Ok(trace) => {
+            cpu.enable_trace(Box::new(trace), filter);
+            true
+        }
+        Err(message) => {
+            warn(message);
+            false
+        }
+    }
+}
+
+fn run_test_mode(
+    filename: &str,
+    model: rboy::HardwareModel,
+    skip_checksum: bool,
+    trace: Option<(&str, rboy::TraceFilter)>,
+) -> i32 {
+    let mut cpu = match Device::new_with_model(filename, skip_checksum, None, model) {
         Err(errmsg) => {
             warn(errmsg);
             return EXITCODE_CPULOADFAILS;
@@ -633,6 +927,12 @@ fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32
         Ok(cpu) => cpu,
     };
 
+    if let Some((path, filter)) = trace {
+        if !start_trace(&mut cpu, path, filter) {
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
     cpu.set_stdout(true);
     cpu.enable_audio(Box::new(NullAudioPlayer {}), false);
 
@@ -0,0 +1,446 @@
+use crate::mbc::{ram_banks, rom_banks, MBC};
+use crate::Error;
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Width of the images taken by the camera sensor
+pub const CAMERA_W: usize = 128;
+/// Height of the images taken by the camera sensor
+pub const CAMERA_H: usize = 112;
+
+const REGISTER_COUNT: usize = 0x36;
+const DITHER_MATRIX: usize = 0x06;
+/// The captured image is stored as 16x14 tiles at the start of RAM bank 0
+const IMAGE_OFFSET: usize = 0x100;
+/// Exposure time at which the brightness of the source image is kept
+const EXPOSURE_REFERENCE: u32 = 0x0800;
+/// Edge enhancement ratios in quarters, from 50% to 500%
+const EDGE_RATIOS: [i32; 8] = [2, 3, 4, 5, 8, 12, 16, 20];
+
+/// Supplies the images seen by the camera sensor.
+pub trait ImageSource: Send {
+    /// Returns `CAMERA_W` x `CAMERA_H` pixels, row by row, from black (0) to white (255)
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+/// A diagonal gradient, used when no other image source is set
+pub struct TestPattern;
+
+impl ImageSource for TestPattern {
+    fn capture(&mut self) -> Vec<u8> {
+        let max = CAMERA_W + CAMERA_H - 2;
+        (0..CAMERA_H)
+            .flat_map(|y| (0..CAMERA_W).map(move |x| ((x + y) * 255 / max) as u8))
+            .collect()
+    }
+}
+
+/// Shows the same image on every capture
+pub struct StaticImage {
+    pixels: Vec<u8>,
+}
+
+impl StaticImage {
+    /// Scales greyscale pixels of an image of `width` x `height` to the size of the sensor
+    pub fn new(pixels: &[u8], width: usize, height: usize) -> Result<StaticImage, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidImage("image is empty"));
+        }
+        match width.checked_mul(height) {
+            Some(size) if pixels.len() >= size => {}
+            _ => return Err(Error::InvalidImage("image is truncated")),
+        }
+        let pixels = (0..CAMERA_H)
+            .flat_map(|y| (0..CAMERA_W).map(move |x| (x, y)))
+            .map(|(x, y)| pixels[(y * height / CAMERA_H) * width + x * width / CAMERA_W])
+            .collect();
+        Ok(StaticImage { pixels })
+    }
+
+    /// Loads a binary PGM (P5) image
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<StaticImage, Error> {
+        let data = std::fs::read(path.as_ref()).map_err(|e| Error::io(path.as_ref(), e))?;
+        StaticImage::from_pgm(&data)
+    }
+
+    pub fn from_pgm(data: &[u8]) -> Result<StaticImage, Error> {
+        let mut pos = 0;
+        let mut fields: [usize; 4] = [0; 4];
+        for field in fields.iter_mut() {
+            // Skip whitespace and comments
+            loop {
+                match data.get(pos) {
+                    Some(b'#') => {
+                        while data.get(pos).is_some_and(|&c| c != b'\n') {
+                            pos += 1;
+                        }
+                    }
+                    Some(c) if c.is_ascii_whitespace() => pos += 1,
+                    _ => break,
+                }
+            }
+            let start = pos;
+            while data.get(pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+                pos += 1;
+            }
+            let token = std::str::from_utf8(&data[start..pos]).unwrap_or("");
+            *field = match token {
+                "P5" if start == 0 => 0,
+                _ if start == 0 => return Err(Error::InvalidImage("not a binary PGM image")),
+                _ => token
+                    .parse()
+                    .map_err(|_| Error::InvalidImage("invalid PGM header"))?,
+            };
+        }
+
+        let [_, width, height, maxval] = fields;
+        if width == 0 || height == 0 || maxval == 0 || maxval > 255 {
+            return Err(Error::InvalidImage("unsupported PGM image size"));
+        }
+        // A single whitespace character separates the header from the pixels
+        let end = width
+            .checked_mul(height)
+            .and_then(|size| size.checked_add(pos + 1))
+            .ok_or(Error::InvalidImage("unsupported PGM image size"))?;
+        let pixels = data
+            .get(pos + 1..end)
+            .ok_or(Error::InvalidImage("PGM image is truncated"))?;
+        let pixels: Vec<u8> = pixels
+            .iter()
+            .map(|&p| (p as usize * 255 / maxval) as u8)
+            .collect();
+        StaticImage::new(&pixels, width, height)
+    }
+}
+
+impl ImageSource for StaticImage {
+    fn capture(&mut self) -> Vec<u8> {
+        self.pixels.clone()
+    }
+}
+
+/// The Pocket Camera, with the camera registers at 0xA000-0xA035 when RAM bank 0x10 is
+/// selected.
+///
+/// The sensor is emulated by processing the image of the `ImageSource` with the exposure time
+/// (0xA002-0xA003), the 2D edge enhancement (0xA001 and 0xA004) and the dither matrix
+/// (0xA006-0xA035). The gain and voltage settings are ignored.
+#[derive(Serialize, Deserialize)]
+pub struct PocketCamera {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rombanks: usize,
+    rambank: usize,
+    rambanks: usize,
+    ram_on: bool,
+    registers_selected: bool,
+    registers: Vec<u8>,
+    /// Ticks until the capture started by writing to 0xA000 completes
+    capture_ticks: u32,
+    ram_updated: bool,
+    #[serde(skip)]
+    source: Option<Box<dyn ImageSource>>,
+}
+
+impl PocketCamera {
+    pub fn new(data: Vec<u8>) -> Result<PocketCamera, Error> {
+        let rombanks = rom_banks(data[0x148]);
+        let rambanks = ram_banks(data[0x149]);
+        Ok(PocketCamera {
+            rom: data,
+            ram: vec![0; rambanks * 0x2000],
+            rombank: 1,
+            rombanks,
+            rambank: 0,
+            rambanks,
+            ram_on: false,
+            registers_selected: false,
+            registers: vec![0; REGISTER_COUNT],
+            capture_ticks: 0,
+            ram_updated: false,
+            source: None,
+        })
+    }
+
+    fn exposure(&self) -> u32 {
+        ((self.registers[2] as u32) << 8) | self.registers[3] as u32
+    }
+
+    fn start_capture(&mut self) {
+        // Capturing takes longer when the N bit is cleared
+        let setup = match self.registers[1] & 0x80 {
+            0 => 2048,
+            _ => 0,
+        };
+        self.capture_ticks = 129792 + setup + self.exposure() * 64;
+    }
+
+    fn finish_capture(&mut self) {
+        let mut image = match self.source.as_mut() {
+            Some(source) => source.capture(),
+            None => TestPattern.capture(),
+        };
+        image.resize(CAMERA_W * CAMERA_H, 0);
+        let tiles = self.process(&image);
+        if self.ram.len() >= IMAGE_OFFSET + tiles.len() {
+            self.ram[IMAGE_OFFSET..IMAGE_OFFSET + tiles.len()].copy_from_slice(&tiles);
+            self.ram_updated = true;
+        }
+        self.registers[0] &= !0x01;
+    }
+
+    /// Converts a source image into the tiles that the sensor writes to RAM
+    fn process(&self, image: &[u8]) -> Vec<u8> {
+        let exposure = self.exposure();
+        let exposed: Vec<i32> = image
+            .iter()
+            .map(|&p| (p as u32 * exposure / EXPOSURE_REFERENCE).min(255) as i32)
+            .collect();
+        let pixel =
+            |x: usize, y: usize| exposed[y.min(CAMERA_H - 1) * CAMERA_W + x.min(CAMERA_W - 1)];
+        let edge_ratio = match self.registers[1] & 0xE0 {
+            0xE0 => Some(EDGE_RATIOS[((self.registers[4] >> 4) & 0x07) as usize]),
+            _ => None,
+        };
+        let invert = self.registers[4] & 0x08 != 0;
+
+        let mut tiles = vec![0; CAMERA_W * CAMERA_H / 4];
+        for y in 0..CAMERA_H {
+            for x in 0..CAMERA_W {
+                let mut value = pixel(x, y);
+                if let Some(ratio) = edge_ratio {
+                    let neighbours = pixel(x.saturating_sub(1), y)
+                        + pixel(x + 1, y)
+                        + pixel(x, y.saturating_sub(1))
+                        + pixel(x, y + 1);
+                    value += (value * 4 - neighbours) * ratio / 4;
+                }
+                let mut value = value.clamp(0, 255) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+
+                let base = DITHER_MATRIX + ((x & 3) + (y & 3) * 4) * 3;
+                let thresholds = &self.registers[base..base + 3];
+                let color = match thresholds.iter().position(|&t| value < t) {
+                    Some(level) => 3 - level as u8,
+                    None => 0,
+                };
+
+                let offset = ((y / 8) * (CAMERA_W / 8) + x / 8) * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+                if color & 1 != 0 {
+                    tiles[offset] |= bit;
+                }
+                if color & 2 != 0 {
+                    tiles[offset + 1] |= bit;
+                }
+            }
+        }
+        tiles
+    }
+}
+
+#[typetag::serde]
+impl MBC for PocketCamera {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        if self.registers_selected {
+            // Only the capture register can be read
+            return match a & 0x7F {
+                0 => self.registers[0],
+                _ => 0x00,
+            };
+        }
+        if self.rambank < self.rambanks {
+            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)]
+        } else {
+            0xFF
+        }
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ram_on = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rombank = (v & 0x3F) as usize % self.rombanks.max(1),
+            0x4000..=0x5FFF => {
+                self.registers_selected = v & 0x10 != 0;
+                self.rambank = (v & 0x0F) as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.registers_selected {
+            match (a & 0x7F) as usize {
+                0 => {
+                    self.registers[0] = v & 0x07;
+                    if v & 0x01 != 0 {
+                        self.start_capture();
+                    }
+                }
+                reg if reg < REGISTER_COUNT => self.registers[reg] = v,
+                _ => {}
+            }
+        } else if self.ram_on && self.rambank < self.rambanks {
+            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+        if ramdata.len() != self.ram.len() {
+            return Err(Error::RamSizeMismatch {
+                expected: self.ram.len(),
+                actual: ramdata.len(),
+            });
+        }
+        self.ram = ramdata.to_vec();
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        if self.registers[0] & 0x01 != 0 {
+            self.capture_ticks = self.capture_ticks.saturating_sub(ticks);
+            if self.capture_ticks == 0 {
+                self.finish_capture();
+            }
+        }
+    }
+
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.source = Some(source);
+    }
+
+    fn take_image_source(&mut self) -> Option<Box<dyn ImageSource>> {
+        self.source.take()
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PocketCamera, StaticImage, CAMERA_H, CAMERA_W};
+    use crate::mbc::MBC;
+
+    fn cartridge() -> PocketCamera {
+        let mut data = vec![0; 0x10000];
+        data[0x147] = 0xFC;
+        data[0x148] = 0x01;
+        data[0x149] = 0x04; // 16 banks of 8 KiB
+        PocketCamera::new(data).unwrap()
+    }
+
+    #[test]
+    fn capture_to_ram() {
+        let mut mbc = cartridge();
+        // The left half of the image is dark grey, the right half light grey
+        let pixels: Vec<u8> = (0..CAMERA_W * CAMERA_H)
+            .map(|i| match i % CAMERA_W < CAMERA_W / 2 {
+                true => 0x50,
+                false => 0xB0,
+            })
+            .collect();
+        mbc.set_image_source(Box::new(
+            StaticImage::new(&pixels, CAMERA_W, CAMERA_H).unwrap(),
+        ));
+
+        mbc.writerom(0x4000, 0x10);
+        mbc.writeram(0xA002, 0x08); // Exposure 0x0800
+        for i in 0..16 {
+            mbc.writeram(0xA006 + i * 3, 0x40);
+            mbc.writeram(0xA007 + i * 3, 0x80);
+            mbc.writeram(0xA008 + i * 3, 0xC0);
+        }
+        mbc.writeram(0xA000, 0x01);
+        assert_eq!(mbc.readram(0xA000), 0x01);
+        // Registers other than 0xA000 read as 0
+        assert_eq!(mbc.readram(0xA002), 0x00);
+
+        mbc.do_cycle(100000);
+        assert_eq!(mbc.readram(0xA000), 0x01);
+        mbc.do_cycle(200000);
+        assert_eq!(mbc.readram(0xA000), 0x00);
+        assert!(mbc.check_and_reset_ram_updated());
+
+        // Dark grey is color 2, light grey color 1
+        mbc.writerom(0x4000, 0x00);
+        assert_eq!((mbc.readram(0xA100), mbc.readram(0xA101)), (0x00, 0xFF));
+        assert_eq!((mbc.readram(0xA1F0), mbc.readram(0xA1F1)), (0xFF, 0x00));
+        assert_eq!(&mbc.dumpram()[0x100..0x102], &[0x00, 0xFF]);
+    }
+
+    #[test]
+    fn pgm_image() {
+        let mut data = b"P5\n# Comment\n2 1\n15\n".to_vec();
+        data.extend_from_slice(&[0, 15]);
+        let image = StaticImage::from_pgm(&data).unwrap();
+        assert_eq!(image.pixels.len(), CAMERA_W * CAMERA_H);
+        assert_eq!(image.pixels[CAMERA_W / 2 - 1], 0);
+        assert_eq!(image.pixels[CAMERA_W / 2], 255);
+
+        assert!(StaticImage::from_pgm(b"P2\n2 1\n15\n0 15").is_err());
+        assert!(StaticImage::from_pgm(b"P5\n2 1\n15\n\x00").is_err());
+        // The size of the pixel data overflows
+        let huge = format!("P5\n{} 2\n255\n", usize::MAX / 2 + 1);
+        assert!(StaticImage::from_pgm(huge.as_bytes()).is_err());
+
+        assert!(StaticImage::new(&[0; 4], 0, 4).is_err());
+        assert!(StaticImage::new(&[0; 4], 2, 3).is_err());
+        assert!(StaticImage::new(&[0; 4], usize::MAX, 2).is_err());
+        assert!(StaticImage::new(&[0; 6], 2, 3).is_ok());
+    }
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use std::time;
+
+/// Number of ticks per second at normal speed
+const TICKS_PER_SECOND: u64 = 4194304;
+
+/// Source of the time used by cartridges with a real time clock.
+#[typetag::serde(tag = "type")]
+pub trait Clock: Send {
+    /// Current time in seconds since the unix epoch
+    fn now(&self) -> u64;
+
+    /// Called with the number of ticks that were emulated
+    fn do_cycle(&mut self, _ticks: u32) {}
+}
+
+/// Follows the clock of the host system. This is what a real cartridge does, but it makes the
+/// emulation depend on when it is run.
+#[derive(Serialize, Deserialize)]
+pub struct WallClock;
+
+#[typetag::serde]
+impl Clock for WallClock {
+    fn now(&self) -> u64 {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Advances with the emulated ticks, so the emulation is deterministic.
+#[derive(Serialize, Deserialize)]
+pub struct EmulatedClock {
+    start: u64,
+    ticks: u64,
+}
+
+impl EmulatedClock {
+    /// Starts the clock at `start` seconds since the unix epoch
+    pub fn new(start: u64) -> EmulatedClock {
+        EmulatedClock { start, ticks: 0 }
+    }
+}
+
+#[typetag::serde]
+impl Clock for EmulatedClock {
+    fn now(&self) -> u64 {
+        self.start + self.ticks / TICKS_PER_SECOND
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        self.ticks += ticks as u64;
+    }
+}
@@ -0,0 +1,195 @@
+use crate::mbc::infrared::InfraredCallback;
+use crate::mbc::{ram_banks, rom_banks, MBC};
+use crate::Error;
+
+use serde::{Deserialize, Serialize};
+
+/// The HuC1, with an infrared port instead of RAM at 0xA000-0xBFFF when 0x0E is written to
+/// 0x0000-0x1FFF.
+#[derive(Serialize, Deserialize)]
+pub struct HuC1 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rombanks: usize,
+    rambank: usize,
+    rambanks: usize,
+    ir_mode: bool,
+    ir_led: bool,
+    ram_updated: bool,
+    #[serde(skip)]
+    infrared: Option<Box<dyn InfraredCallback>>,
+}
+
+impl HuC1 {
+    pub fn new(data: Vec<u8>) -> Result<HuC1, Error> {
+        let rombanks = rom_banks(data[0x148]);
+        let rambanks = ram_banks(data[0x149]);
+        Ok(HuC1 {
+            rom: data,
+            ram: vec![0; rambanks * 0x2000],
+            rombank: 1,
+            rombanks,
+            rambank: 0,
+            rambanks,
+            ir_mode: false,
+            ir_led: false,
+            ram_updated: false,
+            infrared: None,
+        })
+    }
+}
+
+#[typetag::serde]
+impl MBC for HuC1 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        if self.ir_mode {
+            let light = self.infrared.as_ref().is_some_and(|ir| ir.receive());
+            return 0xC0 | light as u8;
+        }
+        if self.rambank < self.rambanks {
+            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)]
+        } else {
+            0xFF
+        }
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ir_mode = v & 0x0F == 0x0E,
+            0x2000..=0x3FFF => self.rombank = (v & 0x3F) as usize % self.rombanks.max(1),
+            0x4000..=0x5FFF => self.rambank = (v & 0x03) as usize,
+            _ => {}
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.ir_mode {
+            let led = v & 0x01 != 0;
+            if led != self.ir_led {
+                self.ir_led = led;
+                if let Some(infrared) = self.infrared.as_mut() {
+                    infrared.transmit(led);
+                }
+            }
+        } else if self.rambank < self.rambanks {
+            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        self.rambanks > 0
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+        if ramdata.len() != self.ram.len() {
+            return Err(Error::RamSizeMismatch {
+                expected: self.ram.len(),
+                actual: ramdata.len(),
+            });
+        }
+        self.ram = ramdata.to_vec();
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_infrared_callback(&mut self, callback: Box<dyn InfraredCallback>) {
+        self.infrared = Some(callback);
+    }
+
+    fn take_infrared_callback(&mut self) -> Option<Box<dyn InfraredCallback>> {
+        self.infrared.take()
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HuC1;
+    use crate::mbc::infrared::InfraredLink;
+    use crate::mbc::MBC;
+
+    fn cartridge() -> HuC1 {
+        let mut data = vec![0; 0x10000];
+        data[0x147] = 0xFF;
+        data[0x148] = 0x01; // 4 banks of 16 KiB
+        data[0x149] = 0x03; // 4 banks of 8 KiB
+        data[0xC000] = 0x33;
+        HuC1::new(data).unwrap()
+    }
+
+    #[test]
+    fn banking_and_infrared() {
+        let mut a = cartridge();
+        let mut b = cartridge();
+        let (link_a, link_b) = InfraredLink::pair();
+        a.set_infrared_callback(Box::new(link_a));
+        b.set_infrared_callback(Box::new(link_b));
+
+        a.writerom(0x2000, 0x03);
+        assert_eq!(a.readrom(0x4000), 0x33);
+        a.writerom(0x4000, 0x01);
+        a.writeram(0xA000, 0x42);
+        assert_eq!(a.ram()[0x2000], 0x42);
+
+        // The RAM is replaced by the infrared port in IR mode
+        a.writerom(0x0000, 0x0E);
+        b.writerom(0x0000, 0x0E);
+        assert_eq!(b.readram(0xA000), 0xC0);
+        a.writeram(0xA000, 0x01);
+        assert_eq!(b.readram(0xA000), 0xC1);
+        assert_eq!(a.readram(0xA000), 0xC0);
+        a.writeram(0xA000, 0x00);
+        assert_eq!(b.readram(0xA000), 0xC0);
+
+        a.writerom(0x0000, 0x0A);
+        assert_eq!(a.readram(0xA000), 0x42);
+    }
+}
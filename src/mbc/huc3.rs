@@ -0,0 +1,384 @@
+use crate::mbc::clock::{Clock, WallClock};
+use crate::mbc::infrared::InfraredCallback;
+use crate::mbc::{ram_banks, rom_banks, MBC};
+use crate::Error;
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// Size of the memory of the RTC chip, which is addressed in nibbles
+const RTC_MEMORY_SIZE: usize = 0x100;
+/// Nibbles 0x00-0x02 hold the minute of the day, 0x03-0x06 the day counter
+const RTC_TIME_NIBBLES: usize = 7;
+/// Nibble holding the tone played by the tone command
+const RTC_TONE_ADDRESS: usize = 0x26;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// Receives the tones of cartridges with a speaker, like the HuC3.
+pub trait ToneCallback: Send {
+    fn tone(&mut self, tone: u8);
+}
+
+/// The HuC3 of Robopon and Pocket Family, with a real time clock that is controlled through
+/// commands, a speaker and an infrared port.
+///
+/// The function of 0xA000-0xBFFF is selected by writing to 0x0000-0x1FFF:
+///
+/// * 0x00: RAM, read only
+/// * 0x0A: RAM, read and write
+/// * 0x0B: write a command to the RTC
+/// * 0x0C: read the response of the RTC
+/// * 0x0D: RTC semaphore, which always reports that the RTC is ready
+/// * 0x0E: infrared port
+#[derive(Serialize, Deserialize)]
+pub struct HuC3 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rombanks: usize,
+    rambank: usize,
+    rambanks: usize,
+    mode: u8,
+    ram_updated: bool,
+    rtc_memory: Vec<u8>,
+    rtc_address: u8,
+    rtc_command: u8,
+    rtc_response: u8,
+    /// Time at which the clock was at day 0, minute 0
+    rtc_zero: u64,
+    clock: Box<dyn Clock>,
+    ir_led: bool,
+    #[serde(skip)]
+    tone_callback: Option<Box<dyn ToneCallback>>,
+    #[serde(skip)]
+    infrared: Option<Box<dyn InfraredCallback>>,
+}
+
+impl HuC3 {
+    pub fn new(data: Vec<u8>) -> Result<HuC3, Error> {
+        let rombanks = rom_banks(data[0x148]);
+        let rambanks = ram_banks(data[0x149]);
+        let clock = Box::new(WallClock);
+        Ok(HuC3 {
+            rom: data,
+            ram: vec![0; rambanks * 0x2000],
+            rombank: 1,
+            rombanks,
+            rambank: 0,
+            rambanks,
+            mode: 0,
+            ram_updated: false,
+            rtc_memory: vec![0; RTC_MEMORY_SIZE],
+            rtc_address: 0,
+            rtc_command: 0,
+            rtc_response: 0,
+            rtc_zero: clock.now(),
+            clock,
+            ir_led: false,
+            tone_callback: None,
+            infrared: None,
+        })
+    }
+
+    /// Minutes elapsed since the clock was at day 0, minute 0
+    fn elapsed_minutes(&self) -> u64 {
+        self.clock.now().wrapping_sub(self.rtc_zero) / 60
+    }
+
+    fn set_elapsed_minutes(&mut self, minutes: u64) {
+        self.rtc_zero = self.clock.now().wrapping_sub(minutes * 60);
+        self.ram_updated = true;
+    }
+
+    fn rtc_command(&mut self, v: u8) {
+        let command = (v >> 4) & 0x07;
+        let argument = v & 0x0F;
+        self.rtc_command = command;
+        match command {
+            0x1 => {
+                self.rtc_response = self.rtc_memory[self.rtc_address as usize];
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            }
+            0x2 | 0x3 => {
+                self.rtc_memory[self.rtc_address as usize] = argument;
+                if command == 0x3 {
+                    self.rtc_address = self.rtc_address.wrapping_add(1);
+                }
+            }
+            0x4 => self.rtc_address = (self.rtc_address & 0xF0) | argument,
+            0x5 => self.rtc_address = (self.rtc_address & 0x0F) | (argument << 4),
+            0x6 => self.rtc_extended_command(argument),
+            _ => {}
+        }
+    }
+
+    fn rtc_extended_command(&mut self, argument: u8) {
+        match argument {
+            0x0 => {
+                let elapsed = self.elapsed_minutes();
+                let time = (elapsed % MINUTES_PER_DAY) | ((elapsed / MINUTES_PER_DAY) << 12);
+                for i in 0..RTC_TIME_NIBBLES {
+                    self.rtc_memory[i] = ((time >> (i * 4)) & 0x0F) as u8;
+                }
+            }
+            0x1 => {
+                let time = (0..RTC_TIME_NIBBLES)
+                    .fold(0, |time, i| time | (self.rtc_memory[i] as u64) << (i * 4));
+                self.set_elapsed_minutes((time & 0xFFF) + (time >> 12) * MINUTES_PER_DAY);
+            }
+            0x2 => self.rtc_response = 0x1,
+            0xE => {
+                let tone = self.rtc_memory[RTC_TONE_ADDRESS];
+                if let Some(callback) = self.tone_callback.as_mut() {
+                    callback.tone(tone);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[typetag::serde]
+impl MBC for HuC3 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        match self.mode {
+            0x00 | 0x0A if self.rambank < self.rambanks => {
+                self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)]
+            }
+            0x0C => (self.rtc_command << 4) | self.rtc_response,
+            0x0D => 0x01,
+            0x0E => {
+                let light = self.infrared.as_ref().is_some_and(|ir| ir.receive());
+                0xC0 | light as u8
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.mode = v & 0x0F,
+            0x2000..=0x3FFF => {
+                self.rombank = match v & 0x7F {
+                    0 => 1,
+                    n => n as usize,
+                } % self.rombanks.max(1)
+            }
+            0x4000..=0x5FFF => self.rambank = (v & 0x03) as usize,
+            _ => {}
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        match self.mode {
+            0x0A if self.rambank < self.rambanks => {
+                self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
+                self.ram_updated = true;
+            }
+            0x0B => self.rtc_command(v),
+            0x0E => {
+                let led = v & 0x01 != 0;
+                if led != self.ir_led {
+                    self.ir_led = led;
+                    if let Some(infrared) = self.infrared.as_mut() {
+                        infrared.transmit(led);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    /// The save starts with the time at which the clock was at zero, like on the MBC3
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+        if ramdata.len() != 8 + self.ram.len() {
+            return Err(Error::RamSizeMismatch {
+                expected: 8 + self.ram.len(),
+                actual: ramdata.len(),
+            });
+        }
+
+        let (int_bytes, rest) = ramdata.split_at(8);
+        self.rtc_zero = u64::from_be_bytes(int_bytes.try_into().unwrap());
+        self.ram = rest.to_vec();
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        let mut data = self.rtc_zero.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.ram);
+        data
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        // Keep the current time of the RTC when switching clocks
+        let elapsed = self.clock.now().wrapping_sub(self.rtc_zero);
+        self.clock = clock;
+        self.rtc_zero = self.clock.now().wrapping_sub(elapsed);
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        self.clock.do_cycle(ticks);
+    }
+
+    fn set_tone_callback(&mut self, callback: Box<dyn ToneCallback>) {
+        self.tone_callback = Some(callback);
+    }
+
+    fn take_tone_callback(&mut self) -> Option<Box<dyn ToneCallback>> {
+        self.tone_callback.take()
+    }
+
+    fn set_infrared_callback(&mut self, callback: Box<dyn InfraredCallback>) {
+        self.infrared = Some(callback);
+    }
+
+    fn take_infrared_callback(&mut self) -> Option<Box<dyn InfraredCallback>> {
+        self.infrared.take()
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HuC3, ToneCallback};
+    use crate::mbc::clock::EmulatedClock;
+    use crate::mbc::MBC;
+    use std::sync::{Arc, Mutex};
+
+    struct SharedTones(Arc<Mutex<Vec<u8>>>);
+
+    impl ToneCallback for SharedTones {
+        fn tone(&mut self, tone: u8) {
+            self.0.lock().unwrap().push(tone);
+        }
+    }
+
+    fn cartridge() -> HuC3 {
+        let mut data = vec![0; 0x10000];
+        data[0x147] = 0xFE;
+        data[0x148] = 0x01; // 4 banks of 16 KiB
+        data[0x149] = 0x03; // 4 banks of 8 KiB
+        data[0xC000] = 0x33;
+        let mut mbc = HuC3::new(data).unwrap();
+        mbc.set_clock(Box::new(EmulatedClock::new(0)));
+        mbc
+    }
+
+    fn command(mbc: &mut HuC3, v: u8) -> u8 {
+        mbc.writerom(0x0000, 0x0B);
+        mbc.writeram(0xA000, v);
+        mbc.writerom(0x0000, 0x0C);
+        mbc.readram(0xA000) & 0x0F
+    }
+
+    /// Reads the time with the read and increment command, as (minutes, days)
+    fn read_time(mbc: &mut HuC3) -> (u16, u16) {
+        command(mbc, 0x60);
+        command(mbc, 0x40);
+        command(mbc, 0x50);
+        let time = (0..7).fold(0, |time, i| time | (command(mbc, 0x10) as u32) << (i * 4));
+        ((time & 0xFFF) as u16, (time >> 12) as u16)
+    }
+
+    #[test]
+    fn banking() {
+        let mut mbc = cartridge();
+        mbc.writerom(0x2000, 0x03);
+        assert_eq!(mbc.readrom(0x4000), 0x33);
+
+        // RAM is only writable in mode 0x0A, but also readable in mode 0x00
+        mbc.writerom(0x4000, 0x02);
+        mbc.writeram(0xA000, 0x42);
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writeram(0xA001, 0x24);
+        mbc.writerom(0x0000, 0x00);
+        assert_eq!(mbc.readram(0xA000), 0x00);
+        assert_eq!(mbc.readram(0xA001), 0x24);
+        assert_eq!(mbc.ram()[0x4001], 0x24);
+
+        mbc.writerom(0x0000, 0x0E);
+        assert_eq!(mbc.readram(0xA000), 0xC0);
+    }
+
+    #[test]
+    fn rtc_commands() {
+        let mut mbc = cartridge();
+        let tones = Arc::new(Mutex::new(Vec::new()));
+        mbc.set_tone_callback(Box::new(SharedTones(tones.clone())));
+
+        // Set the clock to day 2, 23:59
+        command(&mut mbc, 0x40);
+        command(&mut mbc, 0x50);
+        for nibble in [0xF, 0x9, 0x5, 0x2, 0x0, 0x0, 0x0] {
+            command(&mut mbc, 0x30 | nibble);
+        }
+        command(&mut mbc, 0x61);
+        assert_eq!(read_time(&mut mbc), (1439, 2));
+        assert_eq!(command(&mut mbc, 0x62), 0x1);
+
+        for _ in 0..60 {
+            mbc.do_cycle(4194304);
+        }
+        assert_eq!(read_time(&mut mbc), (0, 3));
+
+        // The clock is kept in the save
+        let save = mbc.dumpram();
+        let mut other = cartridge();
+        other.loadram(&save).unwrap();
+        assert_eq!(other.dumpram(), save);
+
+        command(&mut mbc, 0x46);
+        command(&mut mbc, 0x52);
+        command(&mut mbc, 0x25);
+        command(&mut mbc, 0x6E);
+        assert_eq!(*tones.lock().unwrap(), vec![0x5]);
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Connects the infrared port of cartridges like the HuC1 to another device or a test harness.
+pub trait InfraredCallback: Send {
+    /// Called when the cartridge turns its LED on or off
+    fn transmit(&mut self, led_on: bool);
+    /// Whether light reaches the receiver of the cartridge
+    fn receive(&self) -> bool;
+}
+
+/// One end of an infrared connection between two devices, which each receive the LED of the
+/// other end.
+pub struct InfraredLink {
+    led: Arc<AtomicBool>,
+    other_led: Arc<AtomicBool>,
+}
+
+impl InfraredLink {
+    pub fn pair() -> (InfraredLink, InfraredLink) {
+        let a = Arc::new(AtomicBool::new(false));
+        let b = Arc::new(AtomicBool::new(false));
+        (
+            InfraredLink {
+                led: a.clone(),
+                other_led: b.clone(),
+            },
+            InfraredLink {
+                led: b,
+                other_led: a,
+            },
+        )
+    }
+}
+
+impl InfraredCallback for InfraredLink {
+    fn transmit(&mut self, led_on: bool) {
+        self.led.store(led_on, Ordering::Relaxed);
+    }
+
+    fn receive(&self) -> bool {
+        self.other_led.load(Ordering::Relaxed)
+    }
+}
@@ -1,14 +1,15 @@
 use crate::mbc::MBC;
-use crate::StrResult;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct MBC0 {
+    #[serde(skip)]
     rom: Vec<u8>,
 }
 
 impl MBC0 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC0> {
+    pub fn new(data: Vec<u8>) -> Result<MBC0, Error> {
         Ok(MBC0 { rom: data })
     }
 }
@@ -31,12 +32,26 @@ impl MBC for MBC0 {
     fn is_battery_backed(&self) -> bool {
         false
     }
-    fn loadram(&mut self, _ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, _ramdata: &[u8]) -> Result<(), Error> {
         Ok(())
     }
     fn dumpram(&self) -> Vec<u8> {
         Vec::new()
     }
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
     fn check_and_reset_ram_updated(&mut self) -> bool {
         false
     }
@@ -1,9 +1,10 @@
 use crate::mbc::{ram_banks, rom_banks, MBC};
-use crate::StrResult;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct MBC1 {
+    #[serde(skip)]
     rom: Vec<u8>,
     ram: Vec<u8>,
     ram_on: bool,
@@ -14,10 +15,33 @@ pub struct MBC1 {
     has_battery: bool,
     rombanks: usize,
     rambanks: usize,
+    /// MBC1M wiring of compilation cartridges, where the upper bank bits start at bit 4
+    #[serde(default)]
+    multicart: bool,
+}
+
+/// Location of the Nintendo logo in the header of every game
+const LOGO: std::ops::Range<usize> = 0x104..0x134;
+const NINTENDO_LOGO: [u8; 0x30] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+const MULTICART_SIZE: usize = 0x100000;
+const MULTICART_GAME_SIZE: usize = 0x40000;
+
+/// Multicarts contain several 256 KiB games, each starting with its own header
+fn is_multicart(data: &[u8]) -> bool {
+    data.len() == MULTICART_SIZE
+        && (1..MULTICART_SIZE / MULTICART_GAME_SIZE)
+            .map(|game| game * MULTICART_GAME_SIZE)
+            .filter(|offset| data[offset + LOGO.start..offset + LOGO.end] == NINTENDO_LOGO)
+            .count()
+            >= 2
 }
 
 impl MBC1 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC1> {
+    pub fn new(data: Vec<u8>) -> Result<MBC1, Error> {
         let (has_battery, rambanks) = match data[0x147] {
             0x02 => (false, ram_banks(data[0x149])),
             0x03 => (true, ram_banks(data[0x149])),
@@ -25,6 +49,7 @@ impl MBC1 {
         };
         let rombanks = rom_banks(data[0x148]);
         let ramsize = rambanks * 0x2000;
+        let multicart = is_multicart(&data);
 
         let res = MBC1 {
             rom: data,
@@ -37,21 +62,27 @@ impl MBC1 {
             has_battery: has_battery,
             rombanks: rombanks,
             rambanks: rambanks,
+            multicart,
         };
 
         Ok(res)
     }
+
+    /// Number of bank bits set through 0x2000-0x3FFF
+    fn lower_bits(&self) -> usize {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
 }
 
 #[typetag::serde]
 impl MBC for MBC1 {
     fn readrom(&self, a: u16) -> u8 {
         let bank = if a < 0x4000 {
-            if self.banking_mode == 0 {
-                0
-            } else {
-                self.rombank & 0xE0
-            }
+            self.lower_rombank()
         } else {
             self.rombank
         };
@@ -76,16 +107,19 @@ impl MBC for MBC1 {
                 self.ram_on = v & 0xF == 0xA;
             }
             0x2000..=0x3FFF => {
+                let bits = self.lower_bits();
+                // A multicart ignores bit 4, but it still prevents selecting bank 0
                 let lower_bits = match (v as usize) & 0x1F {
                     0 => 1,
                     n => n,
-                };
-                self.rombank = ((self.rombank & 0x60) | lower_bits) % self.rombanks;
+                } & ((1 << bits) - 1);
+                self.rombank = (((self.rombank >> bits) << bits) | lower_bits) % self.rombanks;
             }
             0x4000..=0x5FFF => {
-                if self.rombanks > 0x20 {
-                    let upper_bits = (v as usize & 0x03) % (self.rombanks >> 5);
-                    self.rombank = self.rombank & 0x1F | (upper_bits << 5)
+                let bits = self.lower_bits();
+                if self.rombanks > 1 << bits {
+                    let upper_bits = (v as usize & 0x03) % (self.rombanks >> bits);
+                    self.rombank = self.rombank & ((1 << bits) - 1) | (upper_bits << bits)
                 }
                 if self.rambanks > 1 {
                     self.rambank = (v as usize) & 0x03;
@@ -118,9 +152,12 @@ impl MBC for MBC1 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
         if ramdata.len() != self.ram.len() {
-            return Err("Loaded RAM has incorrect length");
+            return Err(Error::RamSizeMismatch {
+                expected: self.ram.len(),
+                actual: ramdata.len(),
+            });
         }
 
         self.ram = ramdata.to_vec();
@@ -132,9 +169,98 @@ impl MBC for MBC1 {
         self.ram.to_vec()
     }
 
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    /// Mode 1 maps the upper bits of the bank number at 0x0000-0x3FFF as well
+    fn lower_rombank(&self) -> usize {
+        if self.banking_mode == 0 {
+            0
+        } else {
+            (self.rombank >> self.lower_bits()) << self.lower_bits()
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{LOGO, MBC1, MULTICART_GAME_SIZE, MULTICART_SIZE, NINTENDO_LOGO};
+    use crate::mbc::MBC;
+
+    fn rom(logos: usize) -> Vec<u8> {
+        let mut data = vec![0; MULTICART_SIZE];
+        data[0x147] = 0x01;
+        data[0x148] = 0x05;
+        for game in 0..logos {
+            let offset = game * MULTICART_GAME_SIZE;
+            data[offset + LOGO.start..offset + LOGO.end].copy_from_slice(&NINTENDO_LOGO);
+        }
+        for bank in 0..0x40 {
+            data[bank * 0x4000 + 0x1000] = bank as u8;
+        }
+        data
+    }
+
+    #[test]
+    fn multicart_banking() {
+        let mut mbc = MBC1::new(rom(4)).unwrap();
+        assert!(mbc.multicart);
+        // Select the third game, which starts at bank 0x20
+        mbc.writerom(0x4000, 0x02);
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(mbc.readrom(0x1000), 0x20);
+        assert_eq!(mbc.lower_rombank(), 0x20);
+        mbc.writerom(0x2000, 0x03);
+        assert_eq!(mbc.readrom(0x5000), 0x23);
+        // Bit 4 is not connected, but writing 0x10 maps the first bank of the game
+        mbc.writerom(0x2000, 0x10);
+        assert_eq!(mbc.readrom(0x5000), 0x20);
+        mbc.writerom(0x2000, 0x00);
+        assert_eq!(mbc.readrom(0x5000), 0x21);
+
+        // A ROM without any headers is not a multicart
+        assert!(!MBC1::new(rom(0)).unwrap().multicart);
+
+        let mut mbc = MBC1::new(rom(1)).unwrap();
+        assert!(!mbc.multicart);
+        mbc.writerom(0x4000, 0x01);
+        mbc.writerom(0x2000, 0x13);
+        assert_eq!(mbc.readrom(0x5000), 0x33);
+        assert_eq!(mbc.lower_rombank(), 0);
+        // Mode 1 maps bank 0x20 at 0x0000-0x3FFF
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(mbc.lower_rombank(), 0x20);
+        assert_eq!(mbc.readrom(0x1000), 0x20);
+    }
+}
@@ -1,13 +1,14 @@
+use crate::mbc::clock::{Clock, WallClock};
 use crate::mbc::{ram_banks, MBC};
-use crate::StrResult;
+use crate::Error;
 
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::io::prelude::*;
-use std::time;
 
 #[derive(Serialize, Deserialize)]
 pub struct MBC3 {
+    #[serde(skip)]
     rom: Vec<u8>,
     ram: Vec<u8>,
     rombank: usize,
@@ -20,10 +21,11 @@ pub struct MBC3 {
     rtc_ram: [u8; 5],
     rtc_ram_latch: [u8; 5],
     rtc_zero: Option<u64>,
+    clock: Box<dyn Clock>,
 }
 
 impl MBC3 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC3> {
+    pub fn new(data: Vec<u8>) -> Result<MBC3, Error> {
         let subtype = data[0x147];
         let has_battery = match subtype {
             0x0F | 0x10 | 0x13 => true,
@@ -52,6 +54,7 @@ impl MBC3 {
             rtc_ram: [0u8; 5],
             rtc_ram_latch: [0u8; 5],
             rtc_zero: rtc,
+            clock: Box::new(WallClock),
         };
 
         Ok(res)
@@ -69,7 +72,7 @@ impl MBC3 {
         }
 
         let tzero = match self.rtc_zero {
-            Some(t) => time::UNIX_EPOCH + time::Duration::from_secs(t),
+            Some(t) => t,
             None => return,
         };
 
@@ -78,10 +81,7 @@ impl MBC3 {
             return;
         }
 
-        let difftime = match time::SystemTime::now().duration_since(tzero) {
-            Ok(n) => n.as_secs(),
-            _ => 0,
-        };
+        let difftime = self.clock.now().wrapping_sub(tzero);
         self.rtc_ram[0] = (difftime % 60) as u8;
         self.rtc_ram[1] = ((difftime / 60) % 60) as u8;
         self.rtc_ram[2] = ((difftime / 3600) % 24) as u8;
@@ -98,16 +98,14 @@ impl MBC3 {
         if self.rtc_zero.is_none() {
             return None;
         }
-        let mut difftime = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(t) => t.as_secs(),
-            Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
-        };
-        difftime -= self.rtc_ram[0] as u64;
-        difftime -= (self.rtc_ram[1] as u64) * 60;
-        difftime -= (self.rtc_ram[2] as u64) * 3600;
         let days = ((self.rtc_ram[4] as u64 & 0x1) << 8) | (self.rtc_ram[3] as u64);
-        difftime -= days * 3600 * 24;
-        Some(difftime)
+        let elapsed = (self.rtc_ram[0] as u64)
+            + (self.rtc_ram[1] as u64) * 60
+            + (self.rtc_ram[2] as u64) * 3600
+            + days * 3600 * 24;
+        // The clock may be behind the elapsed time, e.g. an emulated clock starting at 0. As
+        // the differences are computed with wrapping arithmetic as well, this works out.
+        Some(self.clock.now().wrapping_sub(elapsed))
     }
 
     fn calc_rtc_zero(&mut self) {
@@ -179,15 +177,19 @@ impl MBC for MBC3 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
         if ramdata.len() != 8 + self.ram.len() {
-            return Err("Loaded ram is too small");
+            return Err(Error::RamSizeMismatch {
+                expected: 8 + self.ram.len(),
+                actual: ramdata.len(),
+            });
         }
 
         let (int_bytes, rest) = ramdata.split_at(8);
         let rtc = u64::from_be_bytes(int_bytes.try_into().unwrap());
         if self.rtc_zero.is_some() {
             self.rtc_zero = Some(rtc);
+            self.calc_rtc_reg();
         }
         self.ram = rest.to_vec();
         Ok(())
@@ -213,9 +215,90 @@ impl MBC for MBC3 {
         file
     }
 
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        // Keep the current time of the RTC when switching clocks
+        self.calc_rtc_reg();
+        self.clock = clock;
+        if self.rtc_zero.is_some() {
+            self.calc_rtc_zero();
+        }
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        self.clock.do_cycle(ticks);
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MBC3;
+    use crate::mbc::clock::EmulatedClock;
+    use crate::mbc::MBC;
+
+    fn latch(mbc: &mut MBC3) -> [u8; 5] {
+        mbc.writerom(0x6000, 0);
+        mbc.writerom(0x6000, 1);
+        let mut regs = [0; 5];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            mbc.writerom(0x4000, 0x08 | i as u8);
+            *reg = mbc.readram(0xA000);
+        }
+        regs
+    }
+
+    #[test]
+    fn emulated_rtc() {
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+        data[0x149] = 0x02;
+        let mut mbc = MBC3::new(data).unwrap();
+        mbc.set_clock(Box::new(EmulatedClock::new(0)));
+        mbc.writerom(0x0000, 0x0A);
+
+        // Start the clock at 1 day, 23:59:58
+        for (i, &v) in [58, 59, 23, 1, 0].iter().enumerate() {
+            mbc.writerom(0x4000, 0x08 | i as u8);
+            mbc.writeram(0xA000, v);
+        }
+        assert_eq!(latch(&mut mbc), [58, 59, 23, 1, 0]);
+
+        for _ in 0..3 {
+            mbc.do_cycle(4194304);
+        }
+        assert_eq!(latch(&mut mbc), [1, 0, 0, 2, 0]);
+    }
+}
@@ -1,9 +1,10 @@
 use crate::mbc::{ram_banks, rom_banks, MBC};
-use crate::StrResult;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct MBC5 {
+    #[serde(skip)]
     rom: Vec<u8>,
     ram: Vec<u8>,
     rombank: usize,
@@ -16,7 +17,7 @@ pub struct MBC5 {
 }
 
 impl MBC5 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC5> {
+    pub fn new(data: Vec<u8>) -> Result<MBC5, Error> {
         let subtype = data[0x147];
         let has_battery = match subtype {
             0x1B | 0x1E => true,
@@ -88,9 +89,12 @@ impl MBC for MBC5 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
         if ramdata.len() != self.ram.len() {
-            return Err("Loaded RAM has incorrect length");
+            return Err(Error::RamSizeMismatch {
+                expected: self.ram.len(),
+                actual: ramdata.len(),
+            });
         }
 
         self.ram = ramdata.to_vec();
@@ -102,6 +106,34 @@ impl MBC for MBC5 {
         self.ram.to_vec()
     }
 
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
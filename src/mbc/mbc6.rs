@@ -0,0 +1,332 @@
+use crate::mbc::MBC;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+
+const RAM_SIZE: usize = 0x8000;
+/// The MX29F008 flash chip of 1 MiB
+const FLASH_SIZE: usize = 0x100000;
+const FLASH_SECTOR_SIZE: usize = 0x20000;
+const FLASH_MANUFACTURER_ID: u8 = 0xC2;
+const FLASH_DEVICE_ID: u8 = 0x81;
+
+#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+enum FlashState {
+    Read,
+    /// Received 0xAA at 0x5555, then 0x55 at 0x2AAA to complete the unlock sequence
+    Unlock1,
+    Unlock2,
+    /// Received the erase command 0x80, which needs a second unlock sequence
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+    /// The next write programs a byte
+    Program,
+    /// Reads return the manufacturer and device id
+    Id,
+}
+
+/// One of the two 8 KiB windows at 0x4000-0x5FFF and 0x6000-0x7FFF
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Window {
+    bank: usize,
+    flash: bool,
+}
+
+/// The MBC6 of Net de Get: Minigame @ 100, with two independently switchable 8 KiB ROM or flash
+/// windows, and two 4 KiB RAM windows at 0xA000-0xAFFF and 0xB000-0xBFFF.
+///
+/// The flash is programmed with the usual unlock sequences at flash addresses 0x5555 and 0x2AAA,
+/// which are bank 2 offset 0x1555 and bank 1 offset 0x0AAA. Programming and erasing complete
+/// immediately. The flash is saved after the RAM.
+#[derive(Serialize, Deserialize)]
+pub struct MBC6 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    flash: Vec<u8>,
+    ram_on: bool,
+    rambanks: [usize; 2],
+    windows: [Window; 2],
+    flash_on: bool,
+    flash_write_on: bool,
+    flash_state: FlashState,
+    ram_updated: bool,
+}
+
+impl MBC6 {
+    pub fn new(data: Vec<u8>) -> Result<MBC6, Error> {
+        Ok(MBC6 {
+            rom: data,
+            ram: vec![0; RAM_SIZE],
+            flash: vec![0xFF; FLASH_SIZE],
+            ram_on: false,
+            rambanks: [0, 0],
+            windows: [Window {
+                bank: 0,
+                flash: false,
+            }; 2],
+            flash_on: false,
+            flash_write_on: false,
+            flash_state: FlashState::Read,
+            ram_updated: false,
+        })
+    }
+
+    fn window(&self, a: u16) -> Window {
+        self.windows[((a as usize) >> 13) & 1]
+    }
+
+    fn ram_index(&self, a: u16) -> usize {
+        let half = ((a as usize) >> 12) & 1;
+        (self.rambanks[half] * 0x1000) | ((a as usize) & 0x0FFF)
+    }
+
+    fn write_flash(&mut self, a: u16, v: u8) {
+        let window = self.window(a);
+        let address = (window.bank * 0x2000) | ((a as usize) & 0x1FFF);
+        let command = address & 0x7FFF;
+        self.flash_state = match (self.flash_state, command, v) {
+            (FlashState::Program, _, _) => {
+                if self.flash_write_on {
+                    // Programming can only clear bits
+                    self.flash[address] &= v;
+                    self.ram_updated = true;
+                }
+                FlashState::Read
+            }
+            (_, _, 0xF0) => FlashState::Read,
+            (FlashState::Read | FlashState::Id, 0x5555, 0xAA) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2AAA, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::Erase,
+            (FlashState::Unlock2, 0x5555, 0xA0) => FlashState::Program,
+            (FlashState::Unlock2, 0x5555, 0x90) => FlashState::Id,
+            (FlashState::Erase, 0x5555, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2AAA, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                self.erase_flash(0..FLASH_SIZE);
+                FlashState::Read
+            }
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                let sector = address & !(FLASH_SECTOR_SIZE - 1);
+                self.erase_flash(sector..sector + FLASH_SECTOR_SIZE);
+                FlashState::Read
+            }
+            (FlashState::Id, _, _) => FlashState::Id,
+            _ => FlashState::Read,
+        };
+    }
+
+    fn erase_flash(&mut self, range: std::ops::Range<usize>) {
+        if self.flash_write_on {
+            self.flash[range].iter_mut().for_each(|b| *b = 0xFF);
+            self.ram_updated = true;
+        }
+    }
+}
+
+#[typetag::serde]
+impl MBC for MBC6 {
+    fn readrom(&self, a: u16) -> u8 {
+        if a < 0x4000 {
+            return *self.rom.get(a as usize).unwrap_or(&0xFF);
+        }
+        let window = self.window(a);
+        let offset = (a as usize) & 0x1FFF;
+        if !window.flash {
+            let banks = (self.rom.len() / 0x2000).max(1);
+            return *self
+                .rom
+                .get(((window.bank % banks) * 0x2000) | offset)
+                .unwrap_or(&0xFF);
+        }
+        if !self.flash_on {
+            return 0xFF;
+        }
+        match self.flash_state {
+            FlashState::Id => match offset & 1 {
+                0 => FLASH_MANUFACTURER_ID,
+                _ => FLASH_DEVICE_ID,
+            },
+            _ => self.flash[(window.bank * 0x2000) | offset],
+        }
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        if !self.ram_on {
+            return 0xFF;
+        }
+        self.ram[self.ram_index(a)]
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x03FF => self.ram_on = v & 0x0F == 0x0A,
+            0x0400..=0x07FF => self.rambanks[0] = (v & 0x07) as usize,
+            0x0800..=0x0BFF => self.rambanks[1] = (v & 0x07) as usize,
+            0x0C00..=0x0FFF => self.flash_on = v & 0x01 != 0,
+            0x1000..=0x1FFF => self.flash_write_on = v & 0x01 != 0,
+            0x2000..=0x27FF => self.windows[0].bank = (v & 0x7F) as usize,
+            0x2800..=0x2FFF => self.windows[0].flash = v == 0x08,
+            0x3000..=0x37FF => self.windows[1].bank = (v & 0x7F) as usize,
+            0x3800..=0x3FFF => self.windows[1].flash = v == 0x08,
+            _ if self.window(a).flash && self.flash_on => self.write_flash(a, v),
+            _ => {}
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.ram_on {
+            return;
+        }
+        let index = self.ram_index(a);
+        self.ram[index] = v;
+        self.ram_updated = true;
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+        if ramdata.len() != RAM_SIZE + FLASH_SIZE {
+            return Err(Error::RamSizeMismatch {
+                expected: RAM_SIZE + FLASH_SIZE,
+                actual: ramdata.len(),
+            });
+        }
+        let (ram, flash) = ramdata.split_at(RAM_SIZE);
+        self.ram = ram.to_vec();
+        self.flash = flash.to_vec();
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&self.flash);
+        data
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    /// The 8 KiB bank mapped at 0x4000-0x5FFF
+    fn rombank(&self) -> usize {
+        self.windows[0].bank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FLASH_DEVICE_ID, FLASH_MANUFACTURER_ID, MBC6, RAM_SIZE};
+    use crate::mbc::MBC;
+
+    fn cartridge() -> MBC6 {
+        let mut data = vec![0; 0x100000];
+        data[0x147] = 0x20;
+        data[0x148] = 0x05;
+        data[0x6000] = 0x33;
+        data[0x8000] = 0x44;
+        MBC6::new(data).unwrap()
+    }
+
+    /// Sends a flash command through the window at 0x6000-0x7FFF
+    fn flash_command(mbc: &mut MBC6, command: u8) {
+        mbc.writerom(0x3000, 2);
+        mbc.writerom(0x7555, 0xAA);
+        mbc.writerom(0x3000, 1);
+        mbc.writerom(0x6AAA, 0x55);
+        mbc.writerom(0x3000, 2);
+        mbc.writerom(0x7555, command);
+    }
+
+    #[test]
+    fn banking() {
+        let mut mbc = cartridge();
+        mbc.writerom(0x2000, 3);
+        mbc.writerom(0x3000, 4);
+        assert_eq!(mbc.readrom(0x4000), 0x33);
+        assert_eq!(mbc.readrom(0x6000), 0x44);
+
+        // The RAM halves are switched independently
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x0400, 1);
+        mbc.writerom(0x0800, 1);
+        mbc.writeram(0xA000, 0x42);
+        assert_eq!(mbc.readram(0xB000), 0x42);
+        mbc.writerom(0x0800, 2);
+        mbc.writeram(0xB001, 0x24);
+        assert_eq!(mbc.ram()[0x1000], 0x42);
+        assert_eq!(mbc.ram()[0x2001], 0x24);
+    }
+
+    #[test]
+    fn flash() {
+        let mut mbc = cartridge();
+        mbc.writerom(0x0C00, 1);
+        mbc.writerom(0x1000, 1);
+        mbc.writerom(0x2800, 0x08);
+        mbc.writerom(0x3800, 0x08);
+
+        flash_command(&mut mbc, 0x90);
+        assert_eq!(mbc.readrom(0x4000), FLASH_MANUFACTURER_ID);
+        assert_eq!(mbc.readrom(0x4001), FLASH_DEVICE_ID);
+        mbc.writerom(0x4000, 0xF0);
+
+        // Program a byte in bank 20, which is in the second sector
+        flash_command(&mut mbc, 0xA0);
+        mbc.writerom(0x2000, 20);
+        mbc.writerom(0x4010, 0x42);
+        assert_eq!(mbc.readrom(0x4010), 0x42);
+        assert_eq!(mbc.readrom(0x4011), 0xFF);
+        assert!(mbc.check_and_reset_ram_updated());
+
+        // The flash is saved after the RAM
+        let save = mbc.dumpram();
+        assert_eq!(save[RAM_SIZE + 20 * 0x2000 + 0x10], 0x42);
+        let mut other = cartridge();
+        other.loadram(&save).unwrap();
+        assert_eq!(other.dumpram(), save);
+
+        // Erase the sector of bank 20
+        flash_command(&mut mbc, 0x80);
+        mbc.writerom(0x3000, 2);
+        mbc.writerom(0x7555, 0xAA);
+        mbc.writerom(0x3000, 1);
+        mbc.writerom(0x6AAA, 0x55);
+        mbc.writerom(0x5000, 0x30);
+        assert_eq!(mbc.readrom(0x4010), 0xFF);
+
+        // Without write enable, the flash cannot be programmed
+        mbc.writerom(0x1000, 0);
+        flash_command(&mut mbc, 0xA0);
+        mbc.writerom(0x4010, 0x00);
+        assert_eq!(mbc.readrom(0x4010), 0xFF);
+    }
+}
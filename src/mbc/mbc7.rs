@@ -0,0 +1,378 @@
+use crate::mbc::{rom_banks, MBC};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+
+/// The 93LC56 holds 128 words of 16 bits
+const EEPROM_SIZE: usize = 256;
+/// Accelerometer reading when the cartridge is level
+const TILT_CENTER: f32 = 0x81D0 as f32;
+/// Change of the accelerometer reading for a tilt of about 1g
+const TILT_RANGE: f32 = 0x70 as f32;
+const UNLATCHED: u16 = 0x8000;
+
+#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+enum EepromState {
+    /// Waiting for a start bit
+    Idle,
+    /// Receiving the opcode and address
+    Command,
+    /// Shifting out the remaining bits of a word
+    Read { word: u16, remaining: u8 },
+    /// Receiving the word to write to an address, or to all addresses
+    Write { address: Option<usize> },
+}
+
+/// A 93LC56 serial EEPROM, controlled through chip select, clock and data lines
+#[derive(Serialize, Deserialize)]
+struct Eeprom {
+    data: Vec<u8>,
+    state: EepromState,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+    shift: u16,
+    bits: u8,
+    write_enabled: bool,
+    updated: bool,
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            data: vec![0xFF; EEPROM_SIZE],
+            state: EepromState::Idle,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+            shift: 0,
+            bits: 0,
+            write_enabled: false,
+            updated: false,
+        }
+    }
+
+    fn word(&self, address: usize) -> u16 {
+        u16::from_le_bytes([self.data[address * 2], self.data[address * 2 + 1]])
+    }
+
+    fn set_word(&mut self, address: usize, word: u16) {
+        if self.write_enabled {
+            self.data[address * 2..address * 2 + 2].copy_from_slice(&word.to_le_bytes());
+            self.updated = true;
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.dout as u8
+    }
+
+    /// Bits are clocked in and out on the rising edge of the clock while chip select is high
+    fn write(&mut self, v: u8) {
+        let cs = v & 0x80 != 0;
+        let clk = v & 0x40 != 0;
+        self.di = v & 0x02 != 0;
+        let rising = clk && !self.clk;
+        self.cs = cs;
+        self.clk = clk;
+        if !cs {
+            self.state = EepromState::Idle;
+            return;
+        }
+        if !rising {
+            return;
+        }
+
+        match self.state {
+            EepromState::Idle => {
+                if self.di {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromState::Command => {
+                self.shift = (self.shift << 1) | self.di as u16;
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.command();
+                }
+            }
+            EepromState::Read { word, remaining } => {
+                let remaining = remaining - 1;
+                self.dout = (word >> remaining) & 1 != 0;
+                self.state = match remaining {
+                    0 => EepromState::Idle,
+                    _ => EepromState::Read { word, remaining },
+                };
+            }
+            EepromState::Write { address } => {
+                self.shift = (self.shift << 1) | self.di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    match address {
+                        Some(address) => self.set_word(address, self.shift),
+                        None => (0..EEPROM_SIZE / 2).for_each(|a| self.set_word(a, self.shift)),
+                    }
+                    self.dout = true;
+                    self.state = EepromState::Idle;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self) {
+        let opcode = self.shift >> 8;
+        // A7 is ignored when addressing words, but selects the commands without an address
+        let address = (self.shift & 0x7F) as usize;
+        let extended = (self.shift >> 6) & 0x03;
+        self.state = EepromState::Idle;
+        self.shift = 0;
+        self.bits = 0;
+        match opcode {
+            0b10 => {
+                // A dummy zero precedes the data
+                self.dout = false;
+                self.state = EepromState::Read {
+                    word: self.word(address),
+                    remaining: 16,
+                };
+            }
+            0b01 => {
+                self.state = EepromState::Write {
+                    address: Some(address),
+                }
+            }
+            0b11 => {
+                self.set_word(address, 0xFFFF);
+                self.dout = true;
+            }
+            _ => match extended {
+                0b11 => self.write_enabled = true,
+                0b00 => self.write_enabled = false,
+                0b10 => {
+                    (0..EEPROM_SIZE / 2).for_each(|a| self.set_word(a, 0xFFFF));
+                    self.dout = true;
+                }
+                _ => self.state = EepromState::Write { address: None },
+            },
+        }
+    }
+}
+
+/// The MBC7 of Kirby Tilt 'n' Tumble and Command Master, with an accelerometer and a serial
+/// EEPROM instead of cartridge RAM
+#[derive(Serialize, Deserialize)]
+pub struct MBC7 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    rombank: usize,
+    rombanks: usize,
+    ram_on: bool,
+    ram_on2: bool,
+    eeprom: Eeprom,
+    /// The tilt set by the frontend, which is only sampled when latched
+    #[serde(skip)]
+    tilt: (f32, f32),
+    latched: (u16, u16),
+    latch_erased: bool,
+}
+
+impl MBC7 {
+    pub fn new(data: Vec<u8>) -> Result<MBC7, Error> {
+        let rombanks = rom_banks(data[0x148]);
+        Ok(MBC7 {
+            rom: data,
+            rombank: 1,
+            rombanks,
+            ram_on: false,
+            ram_on2: false,
+            eeprom: Eeprom::new(),
+            tilt: (0.0, 0.0),
+            latched: (UNLATCHED, UNLATCHED),
+            latch_erased: false,
+        })
+    }
+
+    fn registers_on(&self, a: u16) -> bool {
+        self.ram_on && self.ram_on2 && a < 0xB000
+    }
+}
+
+#[typetag::serde]
+impl MBC for MBC7 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        if !self.registers_on(a) {
+            return 0xFF;
+        }
+        match (a >> 4) & 0xF {
+            0x2 => self.latched.0 as u8,
+            0x3 => (self.latched.0 >> 8) as u8,
+            0x4 => self.latched.1 as u8,
+            0x5 => (self.latched.1 >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ram_on = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rombank = (v as usize & 0x7F) % self.rombanks,
+            0x4000..=0x5FFF => self.ram_on2 = v == 0x40,
+            _ => {}
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.registers_on(a) {
+            return;
+        }
+        match (a >> 4) & 0xF {
+            0x0 if v == 0x55 => {
+                self.latched = (UNLATCHED, UNLATCHED);
+                self.latch_erased = true;
+            }
+            0x1 if v == 0xAA && self.latch_erased => {
+                let axis = |tilt: f32| (TILT_CENTER + tilt.clamp(-1.0, 1.0) * TILT_RANGE) as u16;
+                self.latched = (axis(self.tilt.0), axis(self.tilt.1));
+                self.latch_erased = false;
+            }
+            0x8 => self.eeprom.write(v),
+            _ => {}
+        }
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+        if ramdata.len() != EEPROM_SIZE {
+            return Err(Error::RamSizeMismatch {
+                expected: EEPROM_SIZE,
+                actual: ramdata.len(),
+            });
+        }
+        self.eeprom.data = ramdata.to_vec();
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.eeprom.data.clone()
+    }
+
+    fn loadrom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+    }
+
+    fn unloadrom(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.rom)
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.eeprom.data
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.eeprom.data
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.eeprom.updated;
+        self.eeprom.updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MBC7, TILT_CENTER};
+    use crate::mbc::MBC;
+
+    fn cartridge() -> MBC7 {
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x22;
+        let mut mbc = MBC7::new(data).unwrap();
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x4000, 0x40);
+        mbc
+    }
+
+    /// Clocks bits into the EEPROM and returns the bits it outputs
+    fn clock(mbc: &mut MBC7, bits: &[u8]) -> Vec<u8> {
+        bits.iter()
+            .map(|&bit| {
+                mbc.writeram(0xA080, 0x80 | bit << 1);
+                mbc.writeram(0xA080, 0xC0 | bit << 1);
+                mbc.readram(0xA080) & 1
+            })
+            .collect()
+    }
+
+    fn word_bits(word: u16) -> Vec<u8> {
+        (0..16).rev().map(|i| (word >> i) as u8 & 1).collect()
+    }
+
+    #[test]
+    fn accelerometer() {
+        let mut mbc = cartridge();
+        mbc.set_tilt(1.0, -0.5);
+        // Latching requires erasing first
+        mbc.writeram(0xA010, 0xAA);
+        assert_eq!(mbc.readram(0xA020), 0x00);
+        assert_eq!(mbc.readram(0xA030), 0x80);
+        mbc.writeram(0xA000, 0x55);
+        mbc.writeram(0xA010, 0xAA);
+        let x = mbc.readram(0xA020) as u16 | (mbc.readram(0xA030) as u16) << 8;
+        let y = mbc.readram(0xA040) as u16 | (mbc.readram(0xA050) as u16) << 8;
+        assert_eq!((x, y), (0x8240, 0x8198));
+        assert!(x > TILT_CENTER as u16 && y < TILT_CENTER as u16);
+    }
+
+    #[test]
+    fn eeprom_write_and_read() {
+        let mut mbc = cartridge();
+        // EWEN, then WRITE 0xBEEF to address 5
+        clock(&mut mbc, &[1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0]);
+        mbc.writeram(0xA080, 0x00);
+        let mut write = vec![1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1];
+        write.extend(word_bits(0xBEEF));
+        clock(&mut mbc, &write);
+        mbc.writeram(0xA080, 0x00);
+        assert_eq!(&mbc.dumpram()[10..12], &[0xEF, 0xBE]);
+        assert!(mbc.check_and_reset_ram_updated());
+
+        // READ address 5
+        let output = clock(&mut mbc, &[1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(output[10], 0);
+        assert_eq!(clock(&mut mbc, &[0; 16]), word_bits(0xBEEF));
+    }
+}
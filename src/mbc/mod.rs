@@ -1,15 +1,27 @@
-use crate::StrResult;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
 use std::path;
 
+pub use self::camera::{ImageSource, StaticImage, TestPattern, CAMERA_H, CAMERA_W};
+pub use self::clock::{Clock, EmulatedClock, WallClock};
+pub use self::huc3::ToneCallback;
+pub use self::infrared::{InfraredCallback, InfraredLink};
+
+mod camera;
+mod clock;
+mod huc1;
+mod huc3;
+mod infrared;
 mod mbc0;
 mod mbc1;
 mod mbc2;
 mod mbc3;
 mod mbc5;
+mod mbc6;
+mod mbc7;
 
 #[typetag::serde(tag = "type")]
 pub trait MBC: Send {
@@ -20,9 +32,55 @@ pub trait MBC: Send {
     fn check_and_reset_ram_updated(&mut self) -> bool;
 
     fn is_battery_backed(&self) -> bool;
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()>;
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error>;
     fn dumpram(&self) -> Vec<u8>;
 
+    /// The ROM is not part of the serialized state, and needs to be loaded again after
+    /// deserialization.
+    fn loadrom(&mut self, rom: Vec<u8>);
+    fn unloadrom(&mut self) -> Vec<u8>;
+
+    /// The whole ROM and cartridge RAM, regardless of the mapped banks
+    fn rom(&self) -> &[u8];
+    fn rom_mut(&mut self) -> &mut [u8];
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    /// Replaces the clock of cartridges with a real time clock
+    fn set_clock(&mut self, _clock: Box<dyn Clock>) {}
+    fn do_cycle(&mut self, _ticks: u32) {}
+    /// Tilts cartridges with an accelerometer, from -1.0 to 1.0 on both axes
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+    /// Receives the tones of cartridges with a speaker
+    fn set_tone_callback(&mut self, _callback: Box<dyn ToneCallback>) {}
+    fn take_tone_callback(&mut self) -> Option<Box<dyn ToneCallback>> {
+        None
+    }
+    /// Connects the infrared port of cartridges that have one
+    fn set_infrared_callback(&mut self, _callback: Box<dyn InfraredCallback>) {}
+    fn take_infrared_callback(&mut self) -> Option<Box<dyn InfraredCallback>> {
+        None
+    }
+    /// Supplies the images of cartridges with a camera
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) {}
+    fn take_image_source(&mut self) -> Option<Box<dyn ImageSource>> {
+        None
+    }
+
+    /// The ROM bank mapped at 0x4000-0x7FFF
+    fn rombank(&self) -> usize {
+        1
+    }
+
+    /// The ROM bank mapped at 0x0000-0x3FFF
+    fn lower_rombank(&self) -> usize {
+        0
+    }
+
     fn romname(&self) -> String {
         const TITLE_START: u16 = 0x134;
         const CGB_FLAG: u16 = 0x143;
@@ -43,11 +101,16 @@ pub trait MBC: Send {
 
         result
     }
+
+    /// The global checksum from the header, regardless of the banks mapped at 0x0000-0x3FFF
+    fn global_checksum(&self) -> u16 {
+        global_checksum(self.rom())
+    }
 }
 
-pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC + 'static>> {
+pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> Result<Box<dyn MBC + 'static>, Error> {
     if data.len() < 0x150 {
-        return Err("Rom size to small");
+        return Err(Error::RomTooSmall { size: data.len() });
     }
     if !skip_checksum {
         check_checksum(&data)?;
@@ -58,10 +121,20 @@ pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC + 's
         0x05..=0x06 => mbc2::MBC2::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x0F..=0x13 => mbc3::MBC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x19..=0x1E => mbc5::MBC5::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        _ => Err("Unsupported MBC type"),
+        0x20 => mbc6::MBC6::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        0x22 => mbc7::MBC7::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFC => camera::PocketCamera::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFE => huc3::HuC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFF => huc1::HuC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        cartridge_type => Err(Error::UnsupportedCartridge { cartridge_type }),
     }
 }
 
+/// Receives the cartridge RAM of a battery backed cartridge when it is unloaded.
+pub trait SaveRamSink: Send {
+    fn save(&mut self, ramdata: &[u8]);
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct FileBackedMBC {
     rampath: path::PathBuf,
@@ -69,11 +142,11 @@ pub struct FileBackedMBC {
 }
 
 impl FileBackedMBC {
-    pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> StrResult<FileBackedMBC> {
+    pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> Result<FileBackedMBC, Error> {
         let mut data = vec![];
         File::open(&rompath)
             .and_then(|mut f| f.read_to_end(&mut data))
-            .map_err(|_| "Could not read ROM")?;
+            .map_err(|e| Error::io(&rompath, e))?;
         let mut mbc = get_mbc(data, skip_checksum)?;
 
         let rampath = rompath.with_extension("gbsave");
@@ -83,14 +156,14 @@ impl FileBackedMBC {
                 Ok(mut file) => {
                     let mut ramdata: Vec<u8> = vec![];
                     match file.read_to_end(&mut ramdata) {
-                        Err(..) => return Err("Error while reading existing save file"),
+                        Err(e) => return Err(Error::io(rampath, e)),
                         Ok(..) => {
                             mbc.loadram(&ramdata)?;
                         }
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                Err(_) => return Err("Error loading existing save file"),
+                Err(e) => return Err(Error::io(rampath, e)),
             }
         }
 
@@ -98,42 +171,117 @@ impl FileBackedMBC {
     }
 }
 
-// Implement MBC for FileBackedMBC such that the MMU can use this transparently
-#[typetag::serde]
-impl MBC for FileBackedMBC {
-    fn readrom(&self, a: u16) -> u8 {
-        self.mbc.readrom(a)
-    }
+/// Implements the MBC trait for a wrapper that forwards every call to its `mbc` field
+macro_rules! forward_mbc {
+    ($wrapper:ident) => {
+        #[typetag::serde]
+        impl MBC for $wrapper {
+            fn readrom(&self, a: u16) -> u8 {
+                self.mbc.readrom(a)
+            }
 
-    fn readram(&self, a: u16) -> u8 {
-        self.mbc.readram(a)
-    }
+            fn readram(&self, a: u16) -> u8 {
+                self.mbc.readram(a)
+            }
 
-    fn writerom(&mut self, a: u16, v: u8) {
-        self.mbc.writerom(a, v)
-    }
+            fn writerom(&mut self, a: u16, v: u8) {
+                self.mbc.writerom(a, v)
+            }
 
-    fn writeram(&mut self, a: u16, v: u8) {
-        self.mbc.writeram(a, v)
-    }
+            fn writeram(&mut self, a: u16, v: u8) {
+                self.mbc.writeram(a, v)
+            }
 
-    fn is_battery_backed(&self) -> bool {
-        self.mbc.is_battery_backed()
-    }
+            fn is_battery_backed(&self) -> bool {
+                self.mbc.is_battery_backed()
+            }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
-        self.mbc.loadram(ramdata)
-    }
+            fn loadram(&mut self, ramdata: &[u8]) -> Result<(), Error> {
+                self.mbc.loadram(ramdata)
+            }
 
-    fn dumpram(&self) -> Vec<u8> {
-        self.mbc.dumpram()
-    }
+            fn dumpram(&self) -> Vec<u8> {
+                self.mbc.dumpram()
+            }
 
-    fn check_and_reset_ram_updated(&mut self) -> bool {
-        self.mbc.check_and_reset_ram_updated()
-    }
+            fn loadrom(&mut self, rom: Vec<u8>) {
+                self.mbc.loadrom(rom)
+            }
+
+            fn unloadrom(&mut self) -> Vec<u8> {
+                self.mbc.unloadrom()
+            }
+
+            fn set_clock(&mut self, clock: Box<dyn Clock>) {
+                self.mbc.set_clock(clock)
+            }
+
+            fn do_cycle(&mut self, ticks: u32) {
+                self.mbc.do_cycle(ticks)
+            }
+
+            fn set_tilt(&mut self, x: f32, y: f32) {
+                self.mbc.set_tilt(x, y)
+            }
+
+            fn set_tone_callback(&mut self, callback: Box<dyn ToneCallback>) {
+                self.mbc.set_tone_callback(callback)
+            }
+
+            fn take_tone_callback(&mut self) -> Option<Box<dyn ToneCallback>> {
+                self.mbc.take_tone_callback()
+            }
+
+            fn set_infrared_callback(&mut self, callback: Box<dyn InfraredCallback>) {
+                self.mbc.set_infrared_callback(callback)
+            }
+
+            fn take_infrared_callback(&mut self) -> Option<Box<dyn InfraredCallback>> {
+                self.mbc.take_infrared_callback()
+            }
+
+            fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+                self.mbc.set_image_source(source)
+            }
+
+            fn take_image_source(&mut self) -> Option<Box<dyn ImageSource>> {
+                self.mbc.take_image_source()
+            }
+
+            fn rombank(&self) -> usize {
+                self.mbc.rombank()
+            }
+
+            fn lower_rombank(&self) -> usize {
+                self.mbc.lower_rombank()
+            }
+
+            fn rom(&self) -> &[u8] {
+                self.mbc.rom()
+            }
+
+            fn rom_mut(&mut self) -> &mut [u8] {
+                self.mbc.rom_mut()
+            }
+
+            fn ram(&self) -> &[u8] {
+                self.mbc.ram()
+            }
+
+            fn ram_mut(&mut self) -> &mut [u8] {
+                self.mbc.ram_mut()
+            }
+
+            fn check_and_reset_ram_updated(&mut self) -> bool {
+                self.mbc.check_and_reset_ram_updated()
+            }
+        }
+    };
 }
 
+// Implement MBC for FileBackedMBC such that the MMU can use this transparently
+forward_mbc!(FileBackedMBC);
+
 impl Drop for FileBackedMBC {
     fn drop(&mut self) {
         if self.mbc.is_battery_backed() {
@@ -147,6 +295,40 @@ impl Drop for FileBackedMBC {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SinkBackedMBC {
+    mbc: Box<dyn MBC>,
+    #[serde(skip)]
+    sink: Option<Box<dyn SaveRamSink>>,
+}
+
+impl SinkBackedMBC {
+    pub fn new(
+        data: Vec<u8>,
+        skip_checksum: bool,
+        ramdata: Option<&[u8]>,
+        sink: Option<Box<dyn SaveRamSink>>,
+    ) -> Result<SinkBackedMBC, Error> {
+        let mut mbc = get_mbc(data, skip_checksum)?;
+        if let Some(ramdata) = ramdata {
+            mbc.loadram(ramdata)?;
+        }
+        Ok(SinkBackedMBC { mbc, sink })
+    }
+}
+
+forward_mbc!(SinkBackedMBC);
+
+impl Drop for SinkBackedMBC {
+    fn drop(&mut self) {
+        if self.mbc.is_battery_backed() {
+            if let Some(sink) = &mut self.sink {
+                sink.save(&self.mbc.dumpram());
+            }
+        }
+    }
+}
+
 fn ram_banks(v: u8) -> usize {
     match v {
         1 =>
@@ -172,19 +354,49 @@ fn rom_banks(v: u8) -> usize {
     }
 }
 
-fn check_checksum(data: &[u8]) -> StrResult<()> {
+/// The global checksum at 0x014E-0x014F of a ROM, which identifies the ROM in save states and
+/// movies
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    match rom.get(0x14E..0x150) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+fn check_checksum(data: &[u8]) -> Result<(), Error> {
     let mut value: u8 = 0;
     for i in 0x134..0x14D {
         value = value.wrapping_sub(data[i]).wrapping_sub(1);
     }
     match data[0x14D] == value {
         true => Ok(()),
-        false => Err("Cartridge checksum is invalid"),
+        false => Err(Error::ChecksumMismatch {
+            expected: data[0x14D],
+            actual: value,
+        }),
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::{SaveRamSink, SinkBackedMBC, MBC};
+    use std::sync::{Arc, Mutex};
+
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl SaveRamSink for SharedSink {
+        fn save(&mut self, ramdata: &[u8]) {
+            *self.0.lock().unwrap() = ramdata.to_vec();
+        }
+    }
+
+    fn battery_rom() -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x03; // MBC1+RAM+BATTERY
+        data[0x149] = 0x02; // 1 bank of 8 KiB
+        data
+    }
+
     #[test]
     fn checksum_zero() {
         let mut data = vec![0; 0x150];
@@ -198,4 +410,37 @@ mod test {
         data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
         super::check_checksum(&data).unwrap();
     }
+
+    #[test]
+    fn checksum_mismatch() {
+        let data = vec![0; 0x150];
+        match super::check_checksum(&data) {
+            Err(crate::Error::ChecksumMismatch { expected, actual }) => {
+                assert_eq!(expected, 0);
+                assert_eq!(actual, -(0x14D_i32 - 0x134_i32) as u8);
+            }
+            _ => panic!("Checksum mismatch was not detected"),
+        }
+    }
+
+    #[test]
+    fn sink_receives_ram_on_drop() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut ramdata = vec![0; 0x2000];
+        ramdata[0x10] = 0x42;
+        {
+            let mut mbc = SinkBackedMBC::new(
+                battery_rom(),
+                true,
+                Some(&ramdata),
+                Some(Box::new(SharedSink(saved.clone()))),
+            )
+            .unwrap();
+            mbc.writerom(0x0000, 0x0A);
+            mbc.writeram(0xA011, 0x24);
+        }
+
+        ramdata[0x11] = 0x24;
+        assert_eq!(*saved.lock().unwrap(), ramdata);
+    }
 }
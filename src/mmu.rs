@@ -1,15 +1,44 @@
-use crate::gbmode::{GbMode, GbSpeed};
-use crate::gpu::GPU;
+use crate::cheats::{Cheats, SharkBank};
+use crate::debugger::{Access, Watchpoints};
+use crate::gbmode::{GbMode, GbSpeed, HardwareModel};
+use crate::gpu::{GPU, PALETTE_SIZE};
 use crate::keypad::Keypad;
 use crate::mbc;
 use crate::serial::{Serial, SerialCallback};
+use crate::sgb::Sgb;
 use crate::sound::Sound;
 use crate::timer::Timer;
-use crate::StrResult;
+use crate::Error;
 use serde::{Deserialize, Serialize};
 
 const WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0x7F;
+const IO_SIZE: usize = 0x80;
+const DMG_BOOT_SIZE: usize = 0x100;
+const CGB_BOOT_SIZE: usize = 0x900;
+
+/// A region of memory that can be inspected regardless of the mapped banks
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum MemoryDomain {
+    /// The whole cartridge ROM
+    Rom,
+    /// The whole cartridge RAM
+    CartRam,
+    /// All eight WRAM banks. Only the first two are used in classic mode.
+    Wram,
+    /// Both VRAM banks
+    Vram,
+    /// Sprite attributes at 0xFE00-0xFE9F
+    Oam,
+    /// High RAM at 0xFF80-0xFFFE
+    Hram,
+    /// IO registers at 0xFF00-0xFF7F. These can only be read.
+    Io,
+    /// CGB background palette memory, as exposed through 0xFF69
+    BgPalette,
+    /// CGB sprite palette memory, as exposed through 0xFF6B
+    ObjPalette,
+}
 
 #[derive(PartialEq, Serialize, Deserialize)]
 enum DMAType {
@@ -20,8 +49,7 @@ enum DMAType {
 
 #[derive(Serialize, Deserialize)]
 pub struct MMU {
-    #[serde(with = "serde_arrays")]
-    wram: [u8; WRAM_SIZE],
+    wram: Vec<u8>,
     #[serde(with = "serde_arrays")]
     zram: [u8; ZRAM_SIZE],
     hdma: [u8; 4],
@@ -30,7 +58,7 @@ pub struct MMU {
     pub serial: Serial,
     pub timer: Timer,
     pub keypad: Keypad,
-    pub gpu: GPU,
+    pub gpu: Box<GPU>,
     #[serde(skip)]
     pub sound: Option<Sound>,
     hdma_status: DMAType,
@@ -40,9 +68,21 @@ pub struct MMU {
     wrambank: usize,
     pub mbc: Box<dyn mbc::MBC + 'static>,
     pub gbmode: GbMode,
+    #[serde(default)]
+    pub model: HardwareModel,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
     undocumented_cgb_regs: [u8; 3], // 0xFF72, 0xFF73, 0xFF75
+    /// Mapped over the cartridge ROM until 0xFF50 is written
+    #[serde(default)]
+    boot_rom: Option<Vec<u8>>,
+    /// Only present on the Super Gameboy for games that support it
+    #[serde(default)]
+    pub sgb: Option<Box<Sgb>>,
+    #[serde(skip)]
+    pub watchpoints: Watchpoints,
+    #[serde(skip)]
+    pub cheats: Cheats,
 }
 
 fn fill_random(slice: &mut [u8], start: u32) {
@@ -62,13 +102,14 @@ impl MMU {
     pub fn new(
         cart: Box<dyn mbc::MBC + 'static>,
         serial_callback: Option<Box<dyn SerialCallback>>,
-    ) -> StrResult<MMU> {
+        model: HardwareModel,
+    ) -> Result<MMU, Error> {
         let serial = match serial_callback {
             Some(cb) => Serial::new_with_callback(cb),
             None => Serial::new(),
         };
         let mut res = MMU {
-            wram: [0; WRAM_SIZE],
+            wram: vec![0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
             hdma: [0; 4],
             wrambank: 1,
@@ -77,10 +118,11 @@ impl MMU {
             serial: serial,
             timer: Timer::new(),
             keypad: Keypad::new(),
-            gpu: GPU::new(),
+            gpu: Box::new(GPU::new()),
             sound: None,
             mbc: cart,
             gbmode: GbMode::Classic,
+            model,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
             hdma_src: 0,
@@ -88,10 +130,19 @@ impl MMU {
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
             undocumented_cgb_regs: [0; 3],
+            boot_rom: None,
+            sgb: None,
+            watchpoints: Watchpoints::default(),
+            cheats: Cheats::default(),
         };
         fill_random(&mut res.wram, 42);
+        res.gpu.model = model;
         if res.rb(0x0143) == 0xC0 {
-            return Err("This game does not work in Classic mode");
+            return Err(Error::ColorOnly);
+        }
+        // SGB functions are only enabled when the old licensee code refers to the new one
+        if model == HardwareModel::Sgb && res.rb(0x0146) == 0x03 && res.rb(0x014B) == 0x33 {
+            res.sgb = Some(Box::new(Sgb::new()));
         }
         res.set_initial();
         Ok(res)
@@ -100,13 +151,14 @@ impl MMU {
     pub fn new_cgb(
         cart: Box<dyn mbc::MBC + 'static>,
         serial_callback: Option<Box<dyn SerialCallback>>,
-    ) -> StrResult<MMU> {
+        model: HardwareModel,
+    ) -> Result<MMU, Error> {
         let serial = match serial_callback {
             Some(cb) => Serial::new_with_callback(cb),
             None => Serial::new(),
         };
         let mut res = MMU {
-            wram: [0; WRAM_SIZE],
+            wram: vec![0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
             wrambank: 1,
             hdma: [0; 4],
@@ -115,10 +167,11 @@ impl MMU {
             serial: serial,
             timer: Timer::new(),
             keypad: Keypad::new(),
-            gpu: GPU::new_cgb(),
+            gpu: Box::new(GPU::new_cgb()),
             sound: None,
             mbc: cart,
             gbmode: GbMode::Color,
+            model,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
             hdma_src: 0,
@@ -126,8 +179,13 @@ impl MMU {
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
             undocumented_cgb_regs: [0; 3],
+            boot_rom: None,
+            sgb: None,
+            watchpoints: Watchpoints::default(),
+            cheats: Cheats::default(),
         };
         fill_random(&mut res.wram, 42);
+        res.gpu.model = model;
         res.determine_mode();
         res.set_initial();
         Ok(res)
@@ -167,6 +225,51 @@ impl MMU {
         self.wb(0xFF4B, 0);
     }
 
+    /// Maps a DMG boot ROM of 256 bytes or a CGB boot ROM of 2304 bytes over the cartridge and
+    /// turns off the LCD and sound, so the boot ROM can initialize them. A CGB boot ROM runs in
+    /// color mode and selects the compatibility palettes for classic games.
+    pub fn map_boot_rom(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let expected = match self.gbmode {
+            GbMode::Classic => DMG_BOOT_SIZE,
+            _ => CGB_BOOT_SIZE,
+        };
+        if data.len() != expected {
+            return Err(Error::BootRomSize {
+                expected,
+                actual: data.len(),
+            });
+        }
+        if expected == CGB_BOOT_SIZE {
+            self.gbmode = GbMode::Color;
+            self.gpu.gbmode = GbMode::Color;
+        }
+        self.wb(0xFF40, 0);
+        self.wb(0xFF26, 0);
+        self.boot_rom = Some(data);
+        Ok(())
+    }
+
+    fn unmap_boot_rom(&mut self, value: u8) {
+        if value & 1 == 0 {
+            return;
+        }
+        if let Some(boot_rom) = self.boot_rom.take() {
+            if boot_rom.len() == CGB_BOOT_SIZE {
+                self.determine_mode();
+                self.gpu.compat_palettes = self.gbmode == GbMode::ColorAsClassic;
+            }
+        }
+    }
+
+    fn boot_rom_byte(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF => {
+                self.boot_rom.as_ref()?.get(address as usize).copied()
+            }
+            _ => None,
+        }
+    }
+
     fn determine_mode(&mut self) {
         let mode = match self.rb(0x0143) & 0x80 {
             0x80 => GbMode::Color,
@@ -195,6 +298,8 @@ impl MMU {
 
         let _ = self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
 
+        self.mbc.do_cycle(gputicks);
+
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
 
@@ -202,8 +307,26 @@ impl MMU {
     }
 
     pub fn rb(&mut self, address: u16) -> u8 {
+        let value = self.rb_unwatched(address);
+        self.watchpoints.check(address, value, Access::Read);
+        value
+    }
+
+    /// Reads a byte without triggering watchpoints
+    pub fn rb_unwatched(&mut self, address: u16) -> u8 {
         match address {
-            0x0000..=0x7FFF => self.mbc.readrom(address),
+            0xFF10..=0xFF3F => self.sound.as_mut().map_or(0xFF, |s| s.rb(address)),
+            _ => self.read_mapped(address),
+        }
+    }
+
+    /// Reads a byte from the address space without any side effects
+    fn read_mapped(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => match self.boot_rom_byte(address) {
+                Some(value) => value,
+                None => self.cheats.patch_rom(address, self.mbc.readrom(address)),
+            },
             0x8000..=0x9FFF => self.gpu.rb(address),
             0xA000..=0xBFFF => self.mbc.readram(address),
             0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram[address as usize & 0x0FFF],
@@ -211,11 +334,14 @@ impl MMU {
                 self.wram[(self.wrambank * 0x1000) | address as usize & 0x0FFF]
             }
             0xFE00..=0xFE9F => self.gpu.rb(address),
-            0xFF00 => self.keypad.rb(),
+            0xFF00 => match self.sgb.as_ref() {
+                Some(sgb) => sgb.read_joypad(self.keypad.rb()),
+                None => self.keypad.rb(),
+            },
             0xFF01..=0xFF02 => self.serial.rb(address),
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf | 0b11100000,
-            0xFF10..=0xFF3F => self.sound.as_mut().map_or(0xFF, |s| s.rb(address)),
+            0xFF10..=0xFF3F => self.sound.as_ref().map_or(0xFF, |s| s.peek(address)),
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 if self.gbmode != GbMode::Color => {
                 0xFF
             }
@@ -242,11 +368,98 @@ impl MMU {
         }
     }
 
+    pub fn domain_size(&self, domain: MemoryDomain) -> usize {
+        match domain {
+            MemoryDomain::Rom => self.mbc.rom().len(),
+            MemoryDomain::CartRam => self.mbc.ram().len(),
+            MemoryDomain::Wram => WRAM_SIZE,
+            MemoryDomain::Vram => self.gpu.vram().len(),
+            MemoryDomain::Oam => self.gpu.oam().len(),
+            MemoryDomain::Hram => ZRAM_SIZE,
+            MemoryDomain::Io => IO_SIZE,
+            MemoryDomain::BgPalette | MemoryDomain::ObjPalette => PALETTE_SIZE,
+        }
+    }
+
+    /// Reads a byte at `offset` within `domain` without any side effects
+    pub fn peek(&self, domain: MemoryDomain, offset: usize) -> Option<u8> {
+        if offset >= self.domain_size(domain) {
+            return None;
+        }
+        Some(match domain {
+            MemoryDomain::Rom => self.mbc.rom()[offset],
+            MemoryDomain::CartRam => self.mbc.ram()[offset],
+            MemoryDomain::Wram => self.wram[offset],
+            MemoryDomain::Vram => self.gpu.vram()[offset],
+            MemoryDomain::Oam => self.gpu.oam()[offset],
+            MemoryDomain::Hram => self.zram[offset],
+            MemoryDomain::Io => self.read_mapped(0xFF00 | offset as u16),
+            MemoryDomain::BgPalette => self.gpu.palette_byte(false, offset as u8),
+            MemoryDomain::ObjPalette => self.gpu.palette_byte(true, offset as u8),
+        })
+    }
+
+    /// Writes a byte at `offset` within `domain` without any side effects. Returns false when
+    /// the offset is out of range or the domain cannot be written.
+    pub fn poke(&mut self, domain: MemoryDomain, offset: usize, value: u8) -> bool {
+        if offset >= self.domain_size(domain) {
+            return false;
+        }
+        match domain {
+            MemoryDomain::Rom => self.mbc.rom_mut()[offset] = value,
+            MemoryDomain::CartRam => self.mbc.ram_mut()[offset] = value,
+            MemoryDomain::Wram => self.wram[offset] = value,
+            MemoryDomain::Vram => self.gpu.vram_mut()[offset] = value,
+            MemoryDomain::Oam => self.gpu.oam_mut()[offset] = value,
+            MemoryDomain::Hram => self.zram[offset] = value,
+            MemoryDomain::Io => return false,
+            MemoryDomain::BgPalette => self.gpu.set_palette_byte(false, offset as u8, value),
+            MemoryDomain::ObjPalette => self.gpu.set_palette_byte(true, offset as u8, value),
+        }
+        true
+    }
+
+    /// Performs the writes of the enabled GameShark codes. Codes for a WRAM or cartridge RAM bank
+    /// write to that bank regardless of the mapped one, and banks that do not exist are ignored.
+    /// The writes do not trigger watchpoints.
+    pub fn apply_gameshark(&mut self) {
+        for i in 0..self.cheats.gameshark_writes().len() {
+            let (bank, address, value) = self.cheats.gameshark_writes()[i];
+            let offset = address as usize & 0x0FFF;
+            match (bank, address) {
+                (Some(SharkBank::CartRam(bank)), 0xA000..=0xBFFF) => {
+                    self.poke(
+                        MemoryDomain::CartRam,
+                        bank * 0x2000 + (address as usize & 0x1FFF),
+                        value,
+                    );
+                }
+                (Some(SharkBank::Wram(bank)), 0xD000..=0xDFFF) if self.gbmode == GbMode::Color => {
+                    self.poke(MemoryDomain::Wram, bank * 0x1000 + offset, value);
+                }
+                // Only the MBC knows the mapped cartridge RAM bank
+                (_, 0xA000..=0xBFFF) => self.mbc.writeram(address, value),
+                (_, 0xC000..=0xCFFF) => {
+                    self.poke(MemoryDomain::Wram, offset, value);
+                }
+                _ => {
+                    self.poke(MemoryDomain::Wram, self.wrambank * 0x1000 + offset, value);
+                }
+            }
+        }
+    }
+
     pub fn rw(&mut self, address: u16) -> u16 {
         (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
     }
 
     pub fn wb(&mut self, address: u16, value: u8) {
+        self.watchpoints.check(address, value, Access::Write);
+        self.wb_unwatched(address, value);
+    }
+
+    /// Writes a byte without triggering watchpoints
+    pub fn wb_unwatched(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x7FFF => self.mbc.writerom(address, value),
             0x8000..=0x9FFF => self.gpu.wb(address, value),
@@ -256,11 +469,17 @@ impl MMU {
                 self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value
             }
             0xFE00..=0xFE9F => self.gpu.wb(address, value),
-            0xFF00 => self.keypad.wb(value),
+            0xFF00 => {
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(value, &self.gpu);
+                }
+                self.keypad.wb(value)
+            }
             0xFF01..=0xFF02 => self.serial.wb(address, value),
             0xFF04..=0xFF07 => self.timer.wb(address, value),
             0xFF10..=0xFF3F => self.sound.as_mut().map_or((), |s| s.wb(address, value)),
             0xFF46 => self.oamdma(value),
+            0xFF50 => self.unmap_boot_rom(value),
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 | 0xFF76..=0xFF77
                 if self.gbmode != GbMode::Color => {}
             0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => {}
@@ -306,8 +525,8 @@ impl MMU {
     fn oamdma(&mut self, value: u8) {
         let base = (value as u16) << 8;
         for i in 0..0xA0 {
-            let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+            let b = self.rb_unwatched(base + i);
+            self.wb_unwatched(0xFE00 + i, b);
         }
     }
 
@@ -393,7 +612,7 @@ impl MMU {
     fn perform_vramdma_row(&mut self) {
         let mmu_src = self.hdma_src;
         for j in 0..0x10 {
-            let b: u8 = self.rb(mmu_src + j);
+            let b: u8 = self.rb_unwatched(mmu_src + j);
             self.gpu.wb(self.hdma_dst + j, b);
         }
         self.hdma_src += 0x10;
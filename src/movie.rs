@@ -0,0 +1,165 @@
+use crate::Error;
+
+/// Identifies an rboy movie file
+const MOVIE_MAGIC: &[u8; 4] = b"RBMV";
+/// Increment whenever the layout of the movie file changes
+const MOVIE_VERSION: u16 = 3;
+
+/// A recording of the keypad state at the start of every frame.
+///
+/// Playback is only deterministic when it starts from the same state as the recording. This is
+/// either a freshly constructed `Device` of the same model, or the save state that is
+/// embedded in the movie. Movies that start at power-on embed the battery backed cartridge RAM
+/// and RTC instead, as the save file changes while recording.
+///
+/// Cartridges with an RTC should use an `EmulatedClock` for recording and playback.
+pub struct Movie {
+    pub(crate) rom_checksum: u16,
+    pub(crate) mode: u8,
+    /// The `HardwareModel`, as the models of a mode start with different registers
+    pub(crate) model: u8,
+    pub(crate) start_state: Option<Vec<u8>>,
+    pub(crate) start_ram: Option<Vec<u8>>,
+    pub(crate) frames: Vec<u8>,
+}
+
+impl Movie {
+    /// Number of frames in the movie
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// True when the movie starts from an embedded save state instead of power-on
+    pub fn starts_from_state(&self) -> bool {
+        self.start_state.is_some()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state_len = self.start_state.as_ref().map_or(0, |s| s.len());
+        let ram_len = self.start_ram.as_ref().map_or(0, |r| r.len());
+        let mut data = Vec::with_capacity(23 + state_len + ram_len + self.frames.len());
+        data.extend_from_slice(MOVIE_MAGIC);
+        data.extend_from_slice(&MOVIE_VERSION.to_be_bytes());
+        data.extend_from_slice(&self.rom_checksum.to_be_bytes());
+        data.push(self.mode);
+        data.push(self.model);
+        for part in [&self.start_state, &self.start_ram] {
+            match part {
+                Some(part) => {
+                    data.push(1);
+                    data.extend_from_slice(&(part.len() as u32).to_be_bytes());
+                    data.extend_from_slice(part);
+                }
+                None => data.push(0),
+            }
+        }
+        data.extend_from_slice(&(self.frames.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.frames);
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, Error> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.take(4)? != MOVIE_MAGIC {
+            return Err(Error::InvalidMovie("Not an rboy movie"));
+        }
+        if reader.u16()? != MOVIE_VERSION {
+            return Err(Error::InvalidMovie(
+                "Movie was created by an incompatible version",
+            ));
+        }
+        let rom_checksum = reader.u16()?;
+        let mode = reader.take(1)?[0];
+        let model = reader.take(1)?[0];
+        let start_state = reader.optional()?;
+        let start_ram = reader.optional()?;
+        let len = reader.u32()? as usize;
+        let frames = reader.take(len)?.to_vec();
+
+        Ok(Movie {
+            rom_checksum,
+            mode,
+            model,
+            start_state,
+            start_ram,
+            frames,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < len {
+            return Err(Error::InvalidMovie("Movie is truncated"));
+        }
+        let result = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(result)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a flag, followed by the length and data when the flag is set
+    fn optional(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.take(1)?[0] {
+            0 => Ok(None),
+            1 => {
+                let len = self.u32()? as usize;
+                Ok(Some(self.take(len)?.to_vec()))
+            }
+            _ => Err(Error::InvalidMovie("Unknown movie start type")),
+        }
+    }
+}
+
+/// Drives the keypad of a `Device` from a movie, or records it into one
+pub(crate) enum MovieState {
+    Recording { movie: Movie, pressed: u8 },
+    Playing { movie: Movie, frame: usize },
+}
+
+#[cfg(test)]
+mod test {
+    use super::Movie;
+
+    #[test]
+    fn roundtrip() {
+        for (start_state, start_ram) in [(None, Some(vec![4, 5])), (Some(vec![1, 2, 3]), None)] {
+            let movie = Movie {
+                rom_checksum: 0x1234,
+                mode: 1,
+                model: 4,
+                start_state,
+                start_ram,
+                frames: vec![0, 0x10, 0x11, 0x80],
+            };
+            let data = movie.to_bytes();
+            let decoded = Movie::from_bytes(&data).unwrap();
+            assert_eq!(decoded.rom_checksum, movie.rom_checksum);
+            assert_eq!(decoded.mode, movie.mode);
+            assert_eq!(decoded.model, movie.model);
+            assert_eq!(decoded.start_state, movie.start_state);
+            assert_eq!(decoded.start_ram, movie.start_ram);
+            assert_eq!(decoded.frames, movie.frames);
+
+            assert!(Movie::from_bytes(&data[..data.len() - 1]).is_err());
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::gbmode::GbMode;
+use crate::gbmode::{GbMode, HardwareModel};
 use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Serialize, Deserialize)]
@@ -24,9 +24,21 @@ pub enum CpuFlag {
 }
 
 impl Registers {
-    pub fn new(mode: GbMode) -> Registers {
+    pub fn new(mode: GbMode, model: HardwareModel) -> Registers {
         use CpuFlag::*;
-        match mode {
+        let mut registers = match mode {
+            GbMode::Classic if model == HardwareModel::Sgb => Registers {
+                a: 0x01,
+                f: 0,
+                b: 0x00,
+                c: 0x14,
+                d: 0x00,
+                e: 0x00,
+                h: 0xC0,
+                l: 0x60,
+                pc: 0x0100,
+                sp: 0xFFFE,
+            },
             GbMode::Classic => Registers {
                 a: 0x01,
                 f: C as u8 | H as u8 | Z as u8,
@@ -63,7 +75,17 @@ impl Registers {
                 pc: 0x0100,
                 sp: 0xFFFE,
             },
+        };
+        match model {
+            HardwareModel::Mgb => registers.a = 0xFF,
+            // The AGB boot ROM ends with INC B
+            HardwareModel::Agb => {
+                registers.b = 0x01;
+                registers.f = 0;
+            }
+            _ => {}
         }
+        registers
     }
 
     pub fn af(&self) -> u16 {
@@ -130,11 +152,11 @@ impl Registers {
 mod test {
     use super::CpuFlag::{C, H, N, Z};
     use super::Registers;
-    use crate::gbmode::GbMode;
+    use crate::gbmode::{GbMode, HardwareModel};
 
     #[test]
     fn wide_registers() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(GbMode::Classic, HardwareModel::Dmg);
         reg.a = 0x12;
         reg.setf(0x23);
         reg.b = 0x34;
@@ -160,7 +182,7 @@ mod test {
 
     #[test]
     fn flags() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(GbMode::Classic, HardwareModel::Dmg);
         let flags = [C, H, N, Z];
 
         // Check if initially the flags are good
@@ -179,7 +201,7 @@ mod test {
 
     #[test]
     fn hl_special() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(GbMode::Classic, HardwareModel::Dmg);
         reg.sethl(0x1234);
         assert_eq!(reg.hl(), 0x1234);
         assert_eq!(reg.hld(), 0x1234);
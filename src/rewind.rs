@@ -0,0 +1,207 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Size of the blocks used to find matching data in the reference snapshot
+const BLOCK_SIZE: usize = 16;
+
+const OP_LITERAL: u8 = 0;
+const OP_COPY: u8 = 1;
+
+/// A bounded history of emulator snapshots.
+///
+/// Only the most recent snapshot is kept in full. Every older snapshot is stored as a delta
+/// against the snapshot that followed it, so consecutive states that barely differ take up
+/// little memory.
+pub struct RewindBuffer {
+    interval: u32,
+    capacity: usize,
+    /// Frames run since the state of the most recent snapshot
+    frames: u32,
+    last: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Takes a snapshot every `interval` frames, keeping at most `capacity` snapshots.
+    pub fn new(interval: u32, capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames: 0,
+            last: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Counts a finished frame, returning true when a snapshot should be pushed.
+    pub fn frame_done(&mut self) -> bool {
+        self.frames += 1;
+        self.frames >= self.interval
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        self.frames = 0;
+        if let Some(previous) = self.last.take() {
+            self.deltas.push_back(encode_delta(&previous, &snapshot));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.last = Some(snapshot);
+    }
+
+    /// Returns the most recent snapshot that is older than the current state, with the number
+    /// of frames between them. The snapshot is expected to be restored, so it stays in the
+    /// history and the next snapshot is taken `interval` frames after it.
+    pub fn step_back(&mut self) -> Option<(Vec<u8>, u32)> {
+        if self.frames == 0 {
+            // The current state is the most recent snapshot, which is dropped for the one
+            // before it
+            let delta = self.deltas.pop_back()?;
+            let current = self.last.take()?;
+            self.last = Some(decode_delta(&delta, &current));
+            self.frames = self.interval;
+        }
+        let snapshot = self.last.clone()?;
+        let distance = std::mem::replace(&mut self.frames, 0);
+        Some((snapshot, distance))
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, v: usize) {
+    out.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> usize {
+    let v = u32::from_le_bytes([data[*pos], data[*pos + 1], data[*pos + 2], data[*pos + 3]]);
+    *pos += 4;
+    v as usize
+}
+
+fn flush_literal(out: &mut Vec<u8>, literal: &[u8]) {
+    if literal.is_empty() {
+        return;
+    }
+    out.push(OP_LITERAL);
+    push_u32(out, literal.len());
+    out.extend_from_slice(literal);
+}
+
+/// Encodes `target` as a sequence of literal bytes and copies from `reference`.
+fn encode_delta(target: &[u8], reference: &[u8]) -> Vec<u8> {
+    let mut blocks = HashMap::new();
+    for offset in (0..reference.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+        blocks
+            .entry(&reference[offset..offset + BLOCK_SIZE])
+            .or_insert(offset);
+    }
+
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + BLOCK_SIZE <= target.len() {
+        let offset = match blocks.get(&target[i..i + BLOCK_SIZE]) {
+            Some(&offset) => offset,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut len = BLOCK_SIZE;
+        while i + len < target.len()
+            && offset + len < reference.len()
+            && target[i + len] == reference[offset + len]
+        {
+            len += 1;
+        }
+
+        flush_literal(&mut out, &target[literal_start..i]);
+        out.push(OP_COPY);
+        push_u32(&mut out, offset);
+        push_u32(&mut out, len);
+        i += len;
+        literal_start = i;
+    }
+    flush_literal(&mut out, &target[literal_start..]);
+    out
+}
+
+fn decode_delta(delta: &[u8], reference: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(reference.len());
+    let mut pos = 0;
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_LITERAL => {
+                let len = read_u32(delta, &mut pos);
+                out.extend_from_slice(&delta[pos..pos + len]);
+                pos += len;
+            }
+            OP_COPY => {
+                let offset = read_u32(delta, &mut pos);
+                let len = read_u32(delta, &mut pos);
+                out.extend_from_slice(&reference[offset..offset + len]);
+            }
+            _ => panic!("Invalid rewind delta operation {:02X}", op),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_delta, encode_delta, RewindBuffer};
+
+    fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 23) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_handles_shifted_data() {
+        let reference = pattern(10000, 1);
+        let mut target = reference.clone();
+        target.insert(100, 0xAB);
+        target[5000] ^= 0xFF;
+        target.truncate(9000);
+        target.extend_from_slice(&[1, 2, 3]);
+
+        let delta = encode_delta(&target, &reference);
+        assert!(delta.len() < 200);
+        assert_eq!(decode_delta(&delta, &reference), target);
+    }
+
+    #[test]
+    fn delta_of_unrelated_data() {
+        let reference = pattern(1000, 1);
+        let target = pattern(1500, 2);
+        let delta = encode_delta(&target, &reference);
+        assert_eq!(decode_delta(&delta, &reference), target);
+        assert_eq!(
+            decode_delta(&encode_delta(&[], &reference), &reference),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let mut buffer = RewindBuffer::new(1, 3);
+        for i in 0..5 {
+            let mut snapshot = pattern(1000, 7);
+            snapshot[0] = i;
+            buffer.push(snapshot);
+        }
+        // The snapshot of the current state is skipped
+        for i in (2..4).rev() {
+            let (snapshot, distance) = buffer.step_back().unwrap();
+            assert_eq!((snapshot[0], distance), (i, 1));
+        }
+        assert!(buffer.step_back().is_none());
+    }
+}
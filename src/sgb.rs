@@ -0,0 +1,557 @@
+//! Super Gameboy command packets, palettes and borders
+
+use crate::gpu::{GPU, SCREEN_H, SCREEN_W};
+use serde::{Deserialize, Serialize};
+
+/// Width of the Super Gameboy output, including the border
+pub const SGB_SCREEN_W: usize = 256;
+/// Height of the Super Gameboy output, including the border
+pub const SGB_SCREEN_H: usize = 224;
+
+/// Position of the Gameboy screen within the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+/// The palettes are assigned per 8x8 block of the screen
+const ATTR_W: usize = SCREEN_W / 8;
+const ATTR_H: usize = SCREEN_H / 8;
+/// ATTR_TRN transfers 45 attribute files with 2 bits per block
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = ATTR_W * ATTR_H / 4;
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_W: usize = SGB_SCREEN_W / 8;
+const BORDER_MAP_H: usize = SGB_SCREEN_H / 8;
+const SYSTEM_PALETTES: usize = 512;
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x5294, 0x294A, 0x0000];
+
+#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sgb {
+    /// Number of bits received of the current packet, or None while waiting for a reset pulse
+    receiving: Option<usize>,
+    /// A bit is only read after both P14 and P15 were high
+    ready: bool,
+    packet: [u8; PACKET_SIZE],
+    /// Packets received so far of a command that spans multiple packets
+    command: Vec<u8>,
+    last_lines: u8,
+    palettes: [[u16; 4]; 4],
+    /// Palettes transferred with PAL_TRN, selected with PAL_SET
+    system_palettes: Vec<u16>,
+    /// Palette number of every 8x8 block of the screen
+    attributes: Vec<u8>,
+    /// Attribute files transferred with ATTR_TRN, selected with ATTR_SET and PAL_SET
+    #[serde(default)]
+    attribute_files: Vec<u8>,
+    mask: Mask,
+    frozen: Vec<u8>,
+    /// 4 bits per pixel tiles in the SNES format
+    border_tiles: Vec<u8>,
+    /// 32x28 entries with the tile number, palette and flips
+    border_map: Vec<u16>,
+    /// Border palettes 4 to 7
+    border_palettes: [[u16; 16]; 4],
+    players: u8,
+    player: u8,
+}
+
+fn color(data: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([data[index], data[index + 1]])
+}
+
+fn rgb(color: u16) -> [u8; 3] {
+    let component = |shift: u16| {
+        let c = ((color >> shift) & 0x1F) as u8;
+        (c << 3) | (c >> 2)
+    };
+    [component(0), component(5), component(10)]
+}
+
+/// Color number of a pixel drawn by the GPU in classic mode
+fn shade(grey: u8) -> usize {
+    match grey {
+        255 => 0,
+        192 => 1,
+        96 => 2,
+        _ => 3,
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: None,
+            ready: false,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            last_lines: 0x30,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: vec![0; ATTR_W * ATTR_H],
+            attribute_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            mask: Mask::Cancel,
+            frozen: vec![255; SCREEN_W * SCREEN_H * 3],
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_W * BORDER_MAP_H],
+            border_palettes: [[0; 16]; 4],
+            players: 1,
+            player: 0,
+        }
+    }
+
+    /// Receives the bits of command packets that are sent by pulsing P14 and P15. A pulse on
+    /// both lines starts a packet, then P14 sends a 0 and P15 sends a 1.
+    pub fn write_joypad(&mut self, value: u8, gpu: &GPU) {
+        let lines = value & 0x30;
+        if self.players > 1 && lines == 0x30 && self.last_lines & 0x20 == 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.last_lines = lines;
+
+        match lines {
+            0x00 => {
+                self.receiving = Some(0);
+                self.ready = false;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x30 => self.ready = true,
+            _ if self.ready => {
+                self.ready = false;
+                if let Some(bit) = self.receiving {
+                    if lines == 0x10 {
+                        self.packet[bit / 8] |= 1 << (bit % 8);
+                    }
+                    if bit + 1 == PACKET_BITS {
+                        // The stop bit that follows is ignored
+                        self.receiving = None;
+                        self.receive_packet(gpu);
+                    } else {
+                        self.receiving = Some(bit + 1);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// With multiple players, the lower bits of P1 identify the current player while no keys
+    /// are selected. Only the first player has keys.
+    pub fn read_joypad(&self, value: u8) -> u8 {
+        if self.players == 1 {
+            value
+        } else if value & 0x30 == 0x30 {
+            (value & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            value | 0x0F
+        } else {
+            value
+        }
+    }
+
+    fn receive_packet(&mut self, gpu: &GPU) {
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+            return;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let length = (self.command[0] & 0x07) as usize;
+        if self.command.len() >= length * PACKET_SIZE {
+            let data = std::mem::take(&mut self.command);
+            self.execute(&data, gpu);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8], gpu: &GPU) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => {
+                let transfer = gpu.screen_tile_data();
+                for (i, palette_color) in self.system_palettes.iter_mut().enumerate() {
+                    *palette_color = color(&transfer, i * 2);
+                }
+            }
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => {
+                let half = (data[1] & 0x01) as usize * BORDER_TILES / 2 * BORDER_TILE_SIZE;
+                let transfer = gpu.screen_tile_data();
+                self.border_tiles[half..half + transfer.len()].copy_from_slice(&transfer);
+            }
+            0x14 => {
+                let transfer = gpu.screen_tile_data();
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = color(&transfer, i * 2);
+                }
+                for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (c, palette_color) in palette.iter_mut().enumerate() {
+                        *palette_color = color(&transfer, 0x800 + (p * 16 + c) * 2);
+                    }
+                }
+            }
+            0x15 => {
+                let transfer = gpu.screen_tile_data();
+                self.attribute_files = transfer[..ATTR_FILES * ATTR_FILE_SIZE].to_vec();
+            }
+            0x16 => self.attr_set(data),
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::Cancel,
+                    1 => {
+                        self.frozen.copy_from_slice(&gpu.data);
+                        Mask::Freeze
+                    }
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12 set two palettes and the shared color 0
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color0 = color(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(data, 1 + i * 2);
+            self.palettes[b][i] = color(data, 7 + i * 2);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTR_W && y < ATTR_H {
+            self.attributes[y * ATTR_W + x] = palette & 0x03;
+        }
+    }
+
+    /// Assigns palettes to the inside, border and outside of rectangles
+    fn attr_blk(&mut self, data: &[u8]) {
+        for block in data[2..].chunks_exact(6).take(data[1] as usize) {
+            let mut control = block[0] & 0x07;
+            let inside = block[1] & 0x03;
+            let outside = (block[1] >> 4) & 0x03;
+            let border = match control {
+                // The border takes the palette of the only area that is set
+                0x01 => inside,
+                0x04 => outside,
+                _ => (block[1] >> 2) & 0x03,
+            };
+            if control == 0x01 || control == 0x04 {
+                control |= 0x02;
+            }
+            let (x1, y1, x2, y2) = (
+                block[2] as usize,
+                block[3] as usize,
+                block[4] as usize,
+                block[5] as usize,
+            );
+            for y in 0..ATTR_H {
+                for x in 0..ATTR_W {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_border {
+                        if control & 0x02 != 0 {
+                            self.set_attribute(x, y, border);
+                        }
+                    } else if within {
+                        if control & 0x01 != 0 {
+                            self.set_attribute(x, y, inside);
+                        }
+                    } else if control & 0x04 != 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Assigns palettes to whole rows or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        for &line in data[2..].iter().take(data[1] as usize) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                for x in 0..ATTR_W {
+                    self.set_attribute(x, number, palette);
+                }
+            } else {
+                for y in 0..ATTR_H {
+                    self.set_attribute(number, y, palette);
+                }
+            }
+        }
+    }
+
+    /// Divides the screen in two by a horizontal or vertical line
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+        for y in 0..ATTR_H {
+            for x in 0..ATTR_W {
+                let position = if horizontal { y } else { x };
+                let palette = match position {
+                    p if p < line => before,
+                    p if p == line => on_line,
+                    _ => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    /// Assigns a palette to individual blocks, starting at a position
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = color(data, 3) as usize;
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if x >= ATTR_W || y >= ATTR_H {
+                break;
+            }
+            self.set_attribute(x, y, byte >> (6 - 2 * (i % 4)));
+            if vertical {
+                y += 1;
+                if y == ATTR_H {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_W {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// Selects four of the palettes transferred with PAL_TRN
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = (color(data, 1 + i * 2) as usize % SYSTEM_PALETTES) * 4;
+            self.palettes[i].copy_from_slice(&self.system_palettes[index..index + 4]);
+        }
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file(data[9]);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    /// Selects one of the attribute files transferred with ATTR_TRN
+    fn attr_set(&mut self, data: &[u8]) {
+        self.apply_attribute_file(data[1]);
+        if data[1] & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    /// Copies the attribute file numbered by the lower 6 bits, with 4 blocks per byte starting
+    /// at the upper bits. Numbers beyond the last file are ignored.
+    fn apply_attribute_file(&mut self, number: u8) {
+        let start = (number & 0x3F) as usize * ATTR_FILE_SIZE;
+        if let Some(file) = self.attribute_files.get(start..start + ATTR_FILE_SIZE) {
+            for (i, palette) in self.attributes.iter_mut().enumerate() {
+                *palette = (file[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+            }
+        }
+    }
+
+    /// Colors the screen drawn by the GPU and places it within the border. Returns an RGB image
+    /// of `SGB_SCREEN_W` by `SGB_SCREEN_H` pixels.
+    pub fn render(&self, screen: &[u8]) -> Vec<u8> {
+        let backdrop = rgb(self.palettes[0][0]);
+        let mut frame = backdrop.repeat(SGB_SCREEN_W * SGB_SCREEN_H);
+        let mut set = |x: usize, y: usize, color: [u8; 3]| {
+            let index = (y * SGB_SCREEN_W + x) * 3;
+            frame[index..index + 3].copy_from_slice(&color);
+        };
+
+        let screen = match self.mask {
+            Mask::Freeze => &self.frozen[..],
+            _ => screen,
+        };
+        for y in 0..SCREEN_H {
+            for x in 0..SCREEN_W {
+                let color = match self.mask {
+                    Mask::Black => [0; 3],
+                    Mask::Color0 => backdrop,
+                    Mask::Cancel | Mask::Freeze => {
+                        let colnr = shade(screen[(y * SCREEN_W + x) * 3]);
+                        let palette = self.attributes[(y / 8) * ATTR_W + x / 8] as usize;
+                        match colnr {
+                            0 => backdrop,
+                            _ => rgb(self.palettes[palette][colnr]),
+                        }
+                    }
+                };
+                set(SCREEN_X + x, SCREEN_Y + y, color);
+            }
+        }
+
+        for (i, &entry) in self.border_map.iter().enumerate() {
+            let tile = (entry & 0xFF) as usize * BORDER_TILE_SIZE;
+            let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+            let xflip = entry & (1 << 14) != 0;
+            let yflip = entry & (1 << 15) != 0;
+            for y in 0..8 {
+                let row = tile + if yflip { 7 - y } else { y } * 2;
+                let planes = [
+                    self.border_tiles[row],
+                    self.border_tiles[row + 1],
+                    self.border_tiles[row + 16],
+                    self.border_tiles[row + 17],
+                ];
+                for x in 0..8 {
+                    let bit = if xflip { x } else { 7 - x };
+                    let colnr = planes
+                        .iter()
+                        .enumerate()
+                        .fold(0, |c, (p, plane)| c | (((plane >> bit) & 1) << p));
+                    if colnr != 0 {
+                        set(
+                            (i % BORDER_MAP_W) * 8 + x,
+                            (i / BORDER_MAP_W) * 8 + y,
+                            rgb(palette[colnr as usize]),
+                        );
+                    }
+                }
+            }
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rgb, Mask, Sgb, ATTR_FILE_SIZE, SCREEN_X, SCREEN_Y, SGB_SCREEN_H, SGB_SCREEN_W};
+    use crate::gpu::{GPU, SCREEN_H, SCREEN_W};
+
+    fn send(sgb: &mut Sgb, gpu: &GPU, packet: &[u8]) {
+        sgb.write_joypad(0x00, gpu);
+        for bit in 0..128 {
+            let byte = packet.get(bit / 8).copied().unwrap_or(0);
+            sgb.write_joypad(0x30, gpu);
+            let line = if byte & (1 << (bit % 8)) != 0 {
+                0x10
+            } else {
+                0x20
+            };
+            sgb.write_joypad(line, gpu);
+        }
+        sgb.write_joypad(0x30, gpu);
+        sgb.write_joypad(0x20, gpu);
+        sgb.write_joypad(0x30, gpu);
+    }
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let index = ((SCREEN_Y + y) * SGB_SCREEN_W + SCREEN_X + x) * 3;
+        [frame[index], frame[index + 1], frame[index + 2]]
+    }
+
+    #[test]
+    fn palettes_and_attributes() {
+        let gpu = GPU::new();
+        let mut sgb = Sgb::new();
+        // PAL01: color 0 is blue, palette 1 uses red for color 3
+        send(
+            &mut sgb,
+            &gpu,
+            &[0x01, 0x00, 0x7C, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1F, 0x00],
+        );
+        // ATTR_DIV: the right half of the screen uses palette 1
+        send(&mut sgb, &gpu, &[0x31, 0x01, 10]);
+
+        let mut screen = vec![255; SCREEN_W * SCREEN_H * 3];
+        let right = (SCREEN_W - 1) * 3;
+        screen[right..right + 3].copy_from_slice(&[0; 3]);
+        screen[..3].copy_from_slice(&[0; 3]);
+        let frame = sgb.render(&screen);
+        assert_eq!(frame.len(), SGB_SCREEN_W * SGB_SCREEN_H * 3);
+        assert_eq!(pixel(&frame, SCREEN_W - 1, 0), rgb(0x001F));
+        assert_eq!(pixel(&frame, 0, 0), rgb(0x0000));
+        assert_eq!(pixel(&frame, 1, 0), rgb(0x7C00));
+        // The border area shows color 0
+        assert_eq!(frame[..3], rgb(0x7C00));
+
+        // MASK_EN with black
+        send(&mut sgb, &gpu, &[0xB9, 0x02]);
+        assert_eq!(pixel(&sgb.render(&screen), 1, 0), [0; 3]);
+    }
+
+    #[test]
+    fn attribute_files() {
+        let mut gpu = GPU::new();
+        let mut sgb = Sgb::new();
+        // The transfer is read from the tiles on screen, numbered in order
+        for i in 0..256u16 {
+            gpu.wb(0x9C00 + (i / 20) * 32 + i % 20, i as u8);
+        }
+        // Blocks 1 to 3 of attribute file 1 use palettes 1 to 3
+        gpu.wb(0x8000 + ATTR_FILE_SIZE as u16, 0x1B);
+        send(&mut sgb, &gpu, &[0xA9]);
+
+        // ATTR_SET with file 1, which also cancels the mask
+        send(&mut sgb, &gpu, &[0xB9, 0x02]);
+        send(&mut sgb, &gpu, &[0xB1, 0x41]);
+        assert_eq!(sgb.attributes[..5], [0, 1, 2, 3, 0]);
+        assert!(sgb.mask == Mask::Cancel);
+
+        // PAL_SET only applies an attribute file when bit 7 is set
+        send(&mut sgb, &gpu, &[0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0x00]);
+        assert_eq!(sgb.attributes[..5], [0, 1, 2, 3, 0]);
+        send(&mut sgb, &gpu, &[0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert!(sgb.attributes.iter().all(|&palette| palette == 0));
+
+        // Files beyond the 45 transferred are ignored
+        send(&mut sgb, &gpu, &[0xB1, 0x01]);
+        send(&mut sgb, &gpu, &[0xB1, 0x3F]);
+        assert_eq!(sgb.attributes[..5], [0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let gpu = GPU::new();
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.read_joypad(0xFF), 0xFF);
+        // MLT_REQ for two players
+        send(&mut sgb, &gpu, &[0x89, 0x01]);
+        assert_eq!(sgb.read_joypad(0xFF), 0xFF);
+        sgb.write_joypad(0x10, &gpu);
+        sgb.write_joypad(0x30, &gpu);
+        assert_eq!(sgb.read_joypad(0xFF), 0xFE);
+        assert_eq!(sgb.read_joypad(0xE7), 0xEF);
+    }
+}
@@ -721,7 +721,12 @@ impl Sound {
 
     pub fn rb(&mut self, a: u16) -> u8 {
         self.run();
-        let v = match a {
+        self.peek(a)
+    }
+
+    /// Reads a register without first catching up on the audio output
+    pub fn peek(&self, a: u16) -> u8 {
+        match a {
             0xFF10..=0xFF14 => self.channel1.rb(a),
             0xFF16..=0xFF19 => self.channel2.rb(a),
             0xFF1A..=0xFF1E => self.channel3.rb(a),
@@ -738,8 +743,7 @@ impl Sound {
             }
             0xFF30..=0xFF3F => self.channel3.rb(a),
             _ => 0xFF,
-        };
-        return v;
+        }
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
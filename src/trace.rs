@@ -0,0 +1,94 @@
+use crate::register::Registers;
+use crate::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Receives a line for every executed instruction, in the format used by Gameboy Doctor:
+///
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+///
+/// The line describes the state before the instruction is fetched.
+pub trait TraceCallback: Send {
+    fn trace(&mut self, line: &str);
+}
+
+/// Writes the trace to a file
+pub struct FileTrace {
+    writer: BufWriter<File>,
+}
+
+impl FileTrace {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<FileTrace, Error> {
+        let file = File::create(&path).map_err(|e| Error::io(path.as_ref(), e))?;
+        Ok(FileTrace {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl TraceCallback for FileTrace {
+    fn trace(&mut self, line: &str) {
+        let _ = writeln!(self.writer, "{}", line);
+    }
+}
+
+/// Selects the instructions that are traced. By default, every instruction is traced.
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions with a PC in this range
+    pub pc_range: Option<RangeInclusive<u16>>,
+    /// Only trace instructions in this ROM bank. Instructions outside of ROM are not traced.
+    pub bank: Option<usize>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: u16, bank: Option<usize>) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&pc) {
+                return false;
+            }
+        }
+        match self.bank {
+            Some(wanted) => bank == Some(wanted),
+            None => true,
+        }
+    }
+}
+
+pub(crate) struct Tracer {
+    callback: Box<dyn TraceCallback>,
+    filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn new(callback: Box<dyn TraceCallback>, filter: TraceFilter) -> Tracer {
+        Tracer { callback, filter }
+    }
+
+    /// `pcmem` holds the four bytes starting at PC, and `bank` is the ROM bank mapped at PC
+    pub fn trace(&mut self, reg: &Registers, pcmem: [u8; 4], bank: Option<usize>) {
+        if !self.filter.matches(reg.pc, bank) {
+            return;
+        }
+        let line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            reg.a,
+            reg.af() as u8,
+            reg.b,
+            reg.c,
+            reg.d,
+            reg.e,
+            reg.h,
+            reg.l,
+            reg.sp,
+            reg.pc,
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3]
+        );
+        self.callback.trace(&line);
+    }
+}